@@ -1,27 +1,86 @@
-use bme68x_rust::{CommInterface, Device, Error as BmeError, Interface};
+use crate::recording::RawReading;
+use bme68x_rust::{
+    CommInterface, Device, DeviceConfig, Error as BmeError, Filter, GasHeaterConfig, Interface,
+    Odr, OperationMode, Sample,
+};
+use chrono::Local;
 use embedded_hal::i2c::blocking::I2c;
 use linux_embedded_hal::I2cdev;
-use log::error;
+use log::{debug, error, info, warn};
+use std::fs;
+use std::io::{Error, ErrorKind};
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+/// The two addresses a BME68x can be strapped to.
+pub const ADDRESSES: [u8; 2] = [bme68x_rust::I2C_ADDR_LOW, bme68x_rust::I2C_ADDR_HIGH];
+
+const REG_CHIP_ID: u8 = 0xd0;
+const REG_VARIANT_ID: u8 = 0xf0;
+/// Chip id of both the BME680 and the BME688.
+pub const CHIP_ID: u8 = 0x61;
+
+/// Bits of `SensorData::status`.
+pub const NEW_DATA: u8 = 0x80;
+pub const GAS_VALID: u8 = 0x20;
+pub const HEAT_STAB: u8 = 0x10;
+
+/// Heater setting for a single measurement without BSEC, as in the Bosch
+/// forced mode example.
+const FORCED_HEATER_TEMP: u16 = 300;
+const FORCED_HEATER_DURATION_MS: u16 = 100;
+
+/// Tries of every i2c transfer before it counts as failed, for glitches on
+/// the bus.
+const I2C_ATTEMPTS: u32 = 3;
+const I2C_RETRY_DELAY_US: u32 = 5000;
+
 pub struct I2cDriver {
     pub path: PathBuf,
+    pub address: u8,
     pub device: I2cdev,
+    /// The bus file went away, e.g. with an unplugged USB adapter, and is
+    /// opened again once it is back.
+    lost: bool,
 }
 
-pub fn create_device(path: &Path) -> I2cDriver {
-    let mut device = I2cdev::new(path).expect("Failed to create device");
-    device
-        .set_slave_address(bme68x_rust::I2C_ADDR_HIGH.into())
-        .expect("Cannot set device address");
-    I2cDriver {
-        path: path.to_path_buf(),
-        device: device,
+/// An interface that can be opened again, for `Device::initialize` after a
+/// sensor stopped answering.
+pub trait Reopen: Interface + Sized {
+    fn reopen(&self) -> Result<Self, BmeError>;
+}
+
+pub fn create_device(path: &Path, address: u8) -> Option<I2cDriver> {
+    match open(path, address) {
+        Ok(driver) => Some(driver),
+        Err(message) => {
+            error!(address = address; "{}", message);
+            None
+        }
     }
 }
 
-pub fn init(driver: I2cDriver) -> Option<Device<I2cDriver>> {
+/// Open the bus at `path` for `address`, with the reason it failed.
+fn open(path: &Path, address: u8) -> Result<I2cDriver, String> {
+    let mut device =
+        I2cdev::new(path).map_err(|e| format!("Failed to open {}: {:?}", path.display(), e))?;
+    device.set_slave_address(address.into()).map_err(|e| {
+        format!(
+            "Cannot set device address {:#04x} on {}: {:?}",
+            address,
+            path.display(),
+            e
+        )
+    })?;
+    Ok(I2cDriver {
+        path: path.to_path_buf(),
+        address,
+        device,
+        lost: false,
+    })
+}
+
+pub fn init<I: Interface>(driver: I) -> Option<Device<I>> {
     match Device::initialize(driver) {
         Ok(device) => return Some(device),
         Err(e) => {
@@ -31,6 +90,146 @@ pub fn init(driver: I2cDriver) -> Option<Device<I2cDriver>> {
     }
 }
 
+/// All `/dev/i2c-N` buses, in order of N.
+pub fn buses() -> Vec<PathBuf> {
+    let mut buses: Vec<(u32, PathBuf)> = match fs::read_dir("/dev") {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name();
+                let number = name.to_str()?.strip_prefix("i2c-")?.parse().ok()?;
+                Some((number, entry.path()))
+            })
+            .collect(),
+        Err(e) => {
+            error!("Cannot list /dev: {}", e);
+            Vec::new()
+        }
+    };
+
+    buses.sort();
+
+    buses.into_iter().map(|(_, path)| path).collect()
+}
+
+/// Probe both addresses on every `/dev/i2c-N` bus and return the sensors
+/// that initialize. Most addresses are empty, so only a chip that answers
+/// with the BME68x chip id is initialized, and misses are logged at debug
+/// level.
+pub fn discover() -> Vec<Device<I2cDriver>> {
+    let mut devices = Vec::new();
+
+    for path in buses() {
+        info!("Found i2c Device on {}", path.display());
+
+        for address in ADDRESSES {
+            let mut driver = match open(&path, address) {
+                Ok(driver) => driver,
+                Err(message) => {
+                    debug!("{}", message);
+                    continue;
+                }
+            };
+
+            match read_id(&mut driver) {
+                Ok((CHIP_ID, _)) => {}
+                Ok((chip_id, _)) => {
+                    debug!(
+                        "Chip id {:#04x} at {:#04x} on {} is not a BME68x",
+                        chip_id,
+                        address,
+                        path.display()
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    debug!("Nothing at {:#04x} on {}: {:?}", address, path.display(), e);
+                    continue;
+                }
+            }
+
+            if let Some(device) = init(driver) {
+                info!("Found BME68x at {:#04x} on {}", address, path.display());
+                devices.push(device);
+            }
+        }
+    }
+
+    devices
+}
+
+/// Read the chip id and variant id registers, to tell a BME68x from other
+/// chips on the bus before initializing it.
+pub fn read_id<I: Interface>(interface: &mut I) -> Result<(u8, u8), BmeError> {
+    let mut chip_id = [0];
+    let mut variant_id = [0];
+    interface.read(REG_CHIP_ID, &mut chip_id)?;
+    interface.read(REG_VARIANT_ID, &mut variant_id)?;
+    Ok((chip_id[0], variant_id[0]))
+}
+
+pub fn variant_name(variant_id: u8) -> &'static str {
+    match variant_id {
+        0 => "BME680",
+        1 => "BME688",
+        _ => "unknown BME68x",
+    }
+}
+
+/// Take one forced mode measurement with a fixed heater setting, without
+/// BSEC.
+pub fn measure_once<I: Interface>(device: &mut Device<I>) -> Result<RawReading, Error> {
+    let failed =
+        |what: &str, e: BmeError| Error::new(ErrorKind::Other, format!("{}: {:?}", what, e));
+
+    device
+        .set_config(
+            DeviceConfig::default()
+                .filter(Filter::Size3)
+                .odr(Odr::StandbyNone)
+                .oversample_humidity(Sample::X1)
+                .oversample_temperature(Sample::X1)
+                .oversample_pressure(Sample::X1),
+        )
+        .map_err(|e| failed("Failed setting config", e))?;
+
+    device
+        .set_gas_heater_conf(
+            OperationMode::Forced,
+            GasHeaterConfig::default()
+                .enable()
+                .heater_temp(FORCED_HEATER_TEMP)
+                .heater_duration(FORCED_HEATER_DURATION_MS),
+        )
+        .map_err(|e| failed("Failed setting heater config", e))?;
+
+    let timestamp = Local::now().naive_utc().timestamp_nanos();
+
+    device
+        .set_op_mode(OperationMode::Forced)
+        .map_err(|e| failed("Failed setting operation mode", e))?;
+
+    let delay_period = device.get_measure_duration(OperationMode::Forced)
+        + FORCED_HEATER_DURATION_MS as u32 * 1000;
+    device.interface.delay(delay_period);
+
+    for _ in 0..10 {
+        match device.get_data(OperationMode::Forced) {
+            Ok(data) => {
+                if let Some(field) = data.first().filter(|field| field.status & NEW_DATA != 0) {
+                    return Ok(RawReading::new(timestamp, field));
+                }
+            }
+            Err(BmeError::NoNewData) => {}
+            Err(e) => return Err(failed("Failed reading data", e)),
+        }
+
+        device.interface.delay(10000);
+    }
+
+    Err(Error::new(ErrorKind::TimedOut, "No new data from sensor"))
+}
+
 impl Interface for I2cDriver {
     fn interface_type(&self) -> CommInterface {
         CommInterface::I2C
@@ -43,12 +242,7 @@ impl Interface for I2cDriver {
 
     fn read(&mut self, _reg_addr: u8, _reg_data: &mut [u8]) -> Result<(), BmeError> {
         // Send the address to start reading, then read
-        self.device
-            .write_read(bme68x_rust::I2C_ADDR_HIGH, &[_reg_addr], _reg_data)
-            .map_err(|err| {
-                println!("{:#?}", err);
-                BmeError::CommunicationFailure
-            })
+        self.transfer(|device, address| device.write_read(address, &[_reg_addr], _reg_data))
     }
 
     fn write(&mut self, _reg_addr: u8, _reg_data: &[u8]) -> Result<(), BmeError> {
@@ -59,11 +253,55 @@ impl Interface for I2cDriver {
             bytes.push(b.to_owned());
         }
 
-        self.device
-            .write(bme68x_rust::I2C_ADDR_HIGH, bytes.as_slice())
-            .map_err(|err| {
-                println!("{:#?}", err);
-                BmeError::CommunicationFailure
-            })
+        self.transfer(|device, address| device.write(address, bytes.as_slice()))
+    }
+}
+
+impl I2cDriver {
+    /// Run an i2c transfer, trying again on errors and opening the bus file
+    /// again if it went away.
+    fn transfer<F, E>(&mut self, mut transfer: F) -> Result<(), BmeError>
+    where
+        F: FnMut(&mut I2cdev, u8) -> Result<(), E>,
+        E: std::fmt::Debug,
+    {
+        for attempt in 1..=I2C_ATTEMPTS {
+            if attempt > 1 {
+                self.delay(I2C_RETRY_DELAY_US);
+            }
+
+            if self.lost {
+                match self.reopen() {
+                    Ok(driver) => {
+                        info!(address = self.address; "{} is back", self.path.display());
+                        *self = driver;
+                    }
+                    Err(_) => continue,
+                }
+            }
+
+            match transfer(&mut self.device, self.address) {
+                Ok(()) => return Ok(()),
+                Err(err) => debug!(
+                    address = self.address;
+                    "i2c error at {:#04x}, attempt {}: {:?}", self.address, attempt, err
+                ),
+            }
+
+            // Only look for the bus file once a transfer failed, not on every
+            // read
+            if !self.lost && !self.path.exists() {
+                warn!(address = self.address; "{} is gone", self.path.display());
+                self.lost = true;
+            }
+        }
+
+        Err(BmeError::CommunicationFailure)
+    }
+}
+
+impl Reopen for I2cDriver {
+    fn reopen(&self) -> Result<Self, BmeError> {
+        create_device(&self.path, self.address).ok_or(BmeError::CommunicationFailure)
     }
 }
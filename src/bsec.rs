@@ -5,314 +5,924 @@
 #![allow(unused_must_use)]
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
-use bme68x_rust::SensorData;
-use log::{debug, error, info};
+use crate::recording::RawReading;
+use log::{debug, error, info, warn};
+use std::os::raw::c_void;
+use std::path::Path;
+use std::{fmt, fs, io};
+
+/// Non-zero return codes of the BSEC library.
+///
+/// Negative codes are errors, where the call did not take effect. Positive
+/// codes are warnings, where BSEC completed the call but flagged a problem
+/// with the inputs or the call timing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BsecError {
+    DoStepsInvalidInput,
+    DoStepsValueLimits,
+    DoStepsDuplicateInput,
+    DoStepsExcessOutputs,
+    DoStepsTimestampOutOfRange,
+    DoStepsGasIndexMiss,
+    SubscriptionWrongDataRate,
+    SubscriptionSampleRateLimits,
+    SubscriptionDuplicateGate,
+    SubscriptionInvalidSampleRate,
+    SubscriptionGateCountExceedsArray,
+    SubscriptionSampleIntervalIntegerMult,
+    SubscriptionMultipleGasSampleIntervals,
+    SubscriptionHighHeaterOnDuration,
+    SubscriptionUnknownOutputGate,
+    SubscriptionModeInNonUlp,
+    ParseSectionExceedsWorkBuffer,
+    ConfigFail,
+    ConfigVersionMismatch,
+    ConfigFeatureMismatch,
+    ConfigCrcMismatch,
+    ConfigEmpty,
+    ConfigInsufficientWorkBuffer,
+    ConfigInvalidStringSize,
+    ConfigInsufficientBuffer,
+    SetInvalidChannelIdentifier,
+    SetInvalidLength,
+    CallTimingViolation,
+    ModeExceedsUlpTimeLimit,
+    ModeInsufficientWaitTime,
+    Unknown(i32),
+}
 
-#[derive(Default)]
-pub struct State {
-    pub result: i32,
-    pub mode: f32,
-    pub requested_virtual_sensors: Vec<bsec_sensor_configuration_t>,
-    pub required_sensor_settings: Vec<bsec_sensor_configuration_t>,
-    pub n_required_sensor_settings: u8,
-    pub sensor_settings: bsec_bme_settings_t,
+impl BsecError {
+    pub fn from_code(code: bsec_library_return_t::Type) -> BsecError {
+        match code {
+            bsec_library_return_t::BSEC_E_DOSTEPS_INVALIDINPUT => BsecError::DoStepsInvalidInput,
+            bsec_library_return_t::BSEC_E_DOSTEPS_VALUELIMITS => BsecError::DoStepsValueLimits,
+            bsec_library_return_t::BSEC_E_DOSTEPS_DUPLICATEINPUT => {
+                BsecError::DoStepsDuplicateInput
+            }
+            bsec_library_return_t::BSEC_W_DOSTEPS_EXCESSOUTPUTS => BsecError::DoStepsExcessOutputs,
+            bsec_library_return_t::BSEC_W_DOSTEPS_TSINTRADIFFOUTOFRANGE => {
+                BsecError::DoStepsTimestampOutOfRange
+            }
+            bsec_library_return_t::BSEC_W_DOSTEPS_GASINDEXMISS => BsecError::DoStepsGasIndexMiss,
+            bsec_library_return_t::BSEC_E_SU_WRONGDATARATE => BsecError::SubscriptionWrongDataRate,
+            bsec_library_return_t::BSEC_E_SU_SAMPLERATELIMITS => {
+                BsecError::SubscriptionSampleRateLimits
+            }
+            bsec_library_return_t::BSEC_E_SU_DUPLICATEGATE => BsecError::SubscriptionDuplicateGate,
+            bsec_library_return_t::BSEC_E_SU_INVALIDSAMPLERATE => {
+                BsecError::SubscriptionInvalidSampleRate
+            }
+            bsec_library_return_t::BSEC_E_SU_GATECOUNTEXCEEDSARRAY => {
+                BsecError::SubscriptionGateCountExceedsArray
+            }
+            bsec_library_return_t::BSEC_E_SU_SAMPLINTVLINTEGERMULT => {
+                BsecError::SubscriptionSampleIntervalIntegerMult
+            }
+            bsec_library_return_t::BSEC_E_SU_MULTGASSAMPLINTVL => {
+                BsecError::SubscriptionMultipleGasSampleIntervals
+            }
+            bsec_library_return_t::BSEC_E_SU_HIGHHEATERONDURATION => {
+                BsecError::SubscriptionHighHeaterOnDuration
+            }
+            bsec_library_return_t::BSEC_W_SU_UNKNOWNOUTPUTGATE => {
+                BsecError::SubscriptionUnknownOutputGate
+            }
+            bsec_library_return_t::BSEC_W_SU_MODINNOULP => BsecError::SubscriptionModeInNonUlp,
+            bsec_library_return_t::BSEC_E_PARSE_SECTIONEXCEEDSWORKBUFFER => {
+                BsecError::ParseSectionExceedsWorkBuffer
+            }
+            bsec_library_return_t::BSEC_E_CONFIG_FAIL => BsecError::ConfigFail,
+            bsec_library_return_t::BSEC_E_CONFIG_VERSIONMISMATCH => {
+                BsecError::ConfigVersionMismatch
+            }
+            bsec_library_return_t::BSEC_E_CONFIG_FEATUREMISMATCH => {
+                BsecError::ConfigFeatureMismatch
+            }
+            bsec_library_return_t::BSEC_E_CONFIG_CRCMISMATCH => BsecError::ConfigCrcMismatch,
+            bsec_library_return_t::BSEC_E_CONFIG_EMPTY => BsecError::ConfigEmpty,
+            bsec_library_return_t::BSEC_E_CONFIG_INSUFFICIENTWORKBUFFER => {
+                BsecError::ConfigInsufficientWorkBuffer
+            }
+            bsec_library_return_t::BSEC_E_CONFIG_INVALIDSTRINGSIZE => {
+                BsecError::ConfigInvalidStringSize
+            }
+            bsec_library_return_t::BSEC_E_CONFIG_INSUFFICIENTBUFFER => {
+                BsecError::ConfigInsufficientBuffer
+            }
+            bsec_library_return_t::BSEC_E_SET_INVALIDCHANNELIDENTIFIER => {
+                BsecError::SetInvalidChannelIdentifier
+            }
+            bsec_library_return_t::BSEC_E_SET_INVALIDLENGTH => BsecError::SetInvalidLength,
+            bsec_library_return_t::BSEC_W_SC_CALL_TIMING_VIOLATION => {
+                BsecError::CallTimingViolation
+            }
+            bsec_library_return_t::BSEC_W_SC_MODEXCEEDULPTIMELIMIT => {
+                BsecError::ModeExceedsUlpTimeLimit
+            }
+            bsec_library_return_t::BSEC_W_SC_MODINSUFFICIENTWAITTIME => {
+                BsecError::ModeInsufficientWaitTime
+            }
+            other => BsecError::Unknown(other),
+        }
+    }
+
+    pub fn code(&self) -> i32 {
+        match self {
+            BsecError::DoStepsInvalidInput => bsec_library_return_t::BSEC_E_DOSTEPS_INVALIDINPUT,
+            BsecError::DoStepsValueLimits => bsec_library_return_t::BSEC_E_DOSTEPS_VALUELIMITS,
+            BsecError::DoStepsDuplicateInput => {
+                bsec_library_return_t::BSEC_E_DOSTEPS_DUPLICATEINPUT
+            }
+            BsecError::DoStepsExcessOutputs => bsec_library_return_t::BSEC_W_DOSTEPS_EXCESSOUTPUTS,
+            BsecError::DoStepsTimestampOutOfRange => {
+                bsec_library_return_t::BSEC_W_DOSTEPS_TSINTRADIFFOUTOFRANGE
+            }
+            BsecError::DoStepsGasIndexMiss => bsec_library_return_t::BSEC_W_DOSTEPS_GASINDEXMISS,
+            BsecError::SubscriptionWrongDataRate => bsec_library_return_t::BSEC_E_SU_WRONGDATARATE,
+            BsecError::SubscriptionSampleRateLimits => {
+                bsec_library_return_t::BSEC_E_SU_SAMPLERATELIMITS
+            }
+            BsecError::SubscriptionDuplicateGate => bsec_library_return_t::BSEC_E_SU_DUPLICATEGATE,
+            BsecError::SubscriptionInvalidSampleRate => {
+                bsec_library_return_t::BSEC_E_SU_INVALIDSAMPLERATE
+            }
+            BsecError::SubscriptionGateCountExceedsArray => {
+                bsec_library_return_t::BSEC_E_SU_GATECOUNTEXCEEDSARRAY
+            }
+            BsecError::SubscriptionSampleIntervalIntegerMult => {
+                bsec_library_return_t::BSEC_E_SU_SAMPLINTVLINTEGERMULT
+            }
+            BsecError::SubscriptionMultipleGasSampleIntervals => {
+                bsec_library_return_t::BSEC_E_SU_MULTGASSAMPLINTVL
+            }
+            BsecError::SubscriptionHighHeaterOnDuration => {
+                bsec_library_return_t::BSEC_E_SU_HIGHHEATERONDURATION
+            }
+            BsecError::SubscriptionUnknownOutputGate => {
+                bsec_library_return_t::BSEC_W_SU_UNKNOWNOUTPUTGATE
+            }
+            BsecError::SubscriptionModeInNonUlp => bsec_library_return_t::BSEC_W_SU_MODINNOULP,
+            BsecError::ParseSectionExceedsWorkBuffer => {
+                bsec_library_return_t::BSEC_E_PARSE_SECTIONEXCEEDSWORKBUFFER
+            }
+            BsecError::ConfigFail => bsec_library_return_t::BSEC_E_CONFIG_FAIL,
+            BsecError::ConfigVersionMismatch => {
+                bsec_library_return_t::BSEC_E_CONFIG_VERSIONMISMATCH
+            }
+            BsecError::ConfigFeatureMismatch => {
+                bsec_library_return_t::BSEC_E_CONFIG_FEATUREMISMATCH
+            }
+            BsecError::ConfigCrcMismatch => bsec_library_return_t::BSEC_E_CONFIG_CRCMISMATCH,
+            BsecError::ConfigEmpty => bsec_library_return_t::BSEC_E_CONFIG_EMPTY,
+            BsecError::ConfigInsufficientWorkBuffer => {
+                bsec_library_return_t::BSEC_E_CONFIG_INSUFFICIENTWORKBUFFER
+            }
+            BsecError::ConfigInvalidStringSize => {
+                bsec_library_return_t::BSEC_E_CONFIG_INVALIDSTRINGSIZE
+            }
+            BsecError::ConfigInsufficientBuffer => {
+                bsec_library_return_t::BSEC_E_CONFIG_INSUFFICIENTBUFFER
+            }
+            BsecError::SetInvalidChannelIdentifier => {
+                bsec_library_return_t::BSEC_E_SET_INVALIDCHANNELIDENTIFIER
+            }
+            BsecError::SetInvalidLength => bsec_library_return_t::BSEC_E_SET_INVALIDLENGTH,
+            BsecError::CallTimingViolation => {
+                bsec_library_return_t::BSEC_W_SC_CALL_TIMING_VIOLATION
+            }
+            BsecError::ModeExceedsUlpTimeLimit => {
+                bsec_library_return_t::BSEC_W_SC_MODEXCEEDULPTIMELIMIT
+            }
+            BsecError::ModeInsufficientWaitTime => {
+                bsec_library_return_t::BSEC_W_SC_MODINSUFFICIENTWAITTIME
+            }
+            BsecError::Unknown(code) => *code,
+        }
+    }
+
+    pub fn is_warning(&self) -> bool {
+        match self {
+            BsecError::DoStepsExcessOutputs
+            | BsecError::DoStepsTimestampOutOfRange
+            | BsecError::DoStepsGasIndexMiss
+            | BsecError::SubscriptionUnknownOutputGate
+            | BsecError::SubscriptionModeInNonUlp
+            | BsecError::CallTimingViolation
+            | BsecError::ModeExceedsUlpTimeLimit
+            | BsecError::ModeInsufficientWaitTime => true,
+            BsecError::Unknown(code) => *code > 0,
+            _ => false,
+        }
+    }
 }
 
-pub fn get_version(state: &mut State) -> bsec_version_t {
-    let mut version = bsec_version_t {
-        major: 0,
-        minor: 0,
-        major_bugfix: 0,
-        minor_bugfix: 0,
-    };
+impl fmt::Display for BsecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            BsecError::DoStepsInvalidInput => "input sensor id is invalid",
+            BsecError::DoStepsValueLimits => "input value is out of range",
+            BsecError::DoStepsDuplicateInput => "duplicate input sensor id",
+            BsecError::DoStepsExcessOutputs => "more outputs than the output buffer can hold",
+            BsecError::DoStepsTimestampOutOfRange => "input timestamps differ too much",
+            BsecError::DoStepsGasIndexMiss => "gas index missing from heater profile",
+            BsecError::SubscriptionWrongDataRate => "requested sample rate is not supported",
+            BsecError::SubscriptionSampleRateLimits => "sample rate out of limits",
+            BsecError::SubscriptionDuplicateGate => "duplicate output sensor requested",
+            BsecError::SubscriptionInvalidSampleRate => "invalid sample rate",
+            BsecError::SubscriptionGateCountExceedsArray => {
+                "required sensor settings array is too small"
+            }
+            BsecError::SubscriptionSampleIntervalIntegerMult => {
+                "sample interval is not an integer multiple"
+            }
+            BsecError::SubscriptionMultipleGasSampleIntervals => {
+                "outputs require different gas sample intervals"
+            }
+            BsecError::SubscriptionHighHeaterOnDuration => "heater on duration is too long",
+            BsecError::SubscriptionUnknownOutputGate => "unknown output sensor requested",
+            BsecError::SubscriptionModeInNonUlp => "on-demand measurement outside ULP mode",
+            BsecError::ParseSectionExceedsWorkBuffer => "blob section exceeds work buffer",
+            BsecError::ConfigFail => "blob could not be parsed",
+            BsecError::ConfigVersionMismatch => "blob was made for a different BSEC version",
+            BsecError::ConfigFeatureMismatch => "blob requires features BSEC does not have",
+            BsecError::ConfigCrcMismatch => "blob checksum mismatch",
+            BsecError::ConfigEmpty => "blob is empty",
+            BsecError::ConfigInsufficientWorkBuffer => "work buffer too small for blob",
+            BsecError::ConfigInvalidStringSize => "blob size is invalid",
+            BsecError::ConfigInsufficientBuffer => "buffer too small for blob",
+            BsecError::SetInvalidChannelIdentifier => "invalid channel identifier",
+            BsecError::SetInvalidLength => "invalid blob length",
+            BsecError::CallTimingViolation => "sensor control called at the wrong time",
+            BsecError::ModeExceedsUlpTimeLimit => "on-demand measurement too close to the last one",
+            BsecError::ModeInsufficientWaitTime => "insufficient wait time before measurement",
+            BsecError::Unknown(_) => "unknown return code",
+        };
+        let kind = if self.is_warning() {
+            "warning"
+        } else {
+            "error"
+        };
+        write!(f, "BSEC {} {}: {}", kind, self.code(), description)
+    }
+}
 
-    unsafe {
-        state.result = bsec_get_version(&mut version as *mut bsec_version_t);
+impl std::error::Error for BsecError {}
+
+impl From<BsecError> for io::Error {
+    fn from(err: BsecError) -> Self {
+        io::Error::new(io::ErrorKind::Other, err)
     }
+}
+
+/// Virtual sensor outputs by the names used in `BSEC_OUTPUTS`.
+pub const VIRTUAL_SENSORS: &[(&str, bsec_virtual_sensor_t::Type)] = &[
+    ("iaq", bsec_virtual_sensor_t::BSEC_OUTPUT_IAQ),
+    ("static_iaq", bsec_virtual_sensor_t::BSEC_OUTPUT_STATIC_IAQ),
+    (
+        "co2_equivalent",
+        bsec_virtual_sensor_t::BSEC_OUTPUT_CO2_EQUIVALENT,
+    ),
+    (
+        "breath_voc_equivalent",
+        bsec_virtual_sensor_t::BSEC_OUTPUT_BREATH_VOC_EQUIVALENT,
+    ),
+    (
+        "raw_temperature",
+        bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_TEMPERATURE,
+    ),
+    (
+        "raw_pressure",
+        bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_PRESSURE,
+    ),
+    (
+        "raw_humidity",
+        bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_HUMIDITY,
+    ),
+    ("raw_gas", bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_GAS),
+    (
+        "stabilization_status",
+        bsec_virtual_sensor_t::BSEC_OUTPUT_STABILIZATION_STATUS,
+    ),
+    (
+        "run_in_status",
+        bsec_virtual_sensor_t::BSEC_OUTPUT_RUN_IN_STATUS,
+    ),
+    (
+        "heat_compensated_temperature",
+        bsec_virtual_sensor_t::BSEC_OUTPUT_SENSOR_HEAT_COMPENSATED_TEMPERATURE,
+    ),
+    (
+        "heat_compensated_humidity",
+        bsec_virtual_sensor_t::BSEC_OUTPUT_SENSOR_HEAT_COMPENSATED_HUMIDITY,
+    ),
+    (
+        "compensated_gas",
+        bsec_virtual_sensor_t::BSEC_OUTPUT_COMPENSATED_GAS,
+    ),
+    (
+        "gas_percentage",
+        bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_PERCENTAGE,
+    ),
+    (
+        "gas_estimate_1",
+        bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_1,
+    ),
+    (
+        "gas_estimate_2",
+        bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_2,
+    ),
+    (
+        "gas_estimate_3",
+        bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_3,
+    ),
+    (
+        "gas_estimate_4",
+        bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_4,
+    ),
+    (
+        "raw_gas_index",
+        bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_GAS_INDEX,
+    ),
+];
+
+pub fn virtual_sensor_name(sensor_id: u8) -> &'static str {
+    VIRTUAL_SENSORS
+        .iter()
+        .find(|(_, id)| *id == sensor_id as u32)
+        .map(|(name, _)| *name)
+        .unwrap_or("unknown")
+}
 
-    info!(
-        "BSEC Version: {}.{}.{}",
-        version.major, version.minor, version.major_bugfix
-    );
+pub fn physical_sensor_name(sensor_id: u8) -> &'static str {
+    match sensor_id as u32 {
+        bsec_physical_sensor_t::BSEC_INPUT_PRESSURE => "pressure",
+        bsec_physical_sensor_t::BSEC_INPUT_HUMIDITY => "humidity",
+        bsec_physical_sensor_t::BSEC_INPUT_TEMPERATURE => "temperature",
+        bsec_physical_sensor_t::BSEC_INPUT_GASRESISTOR => "gas resistance",
+        bsec_physical_sensor_t::BSEC_INPUT_HEATSOURCE => "heat source",
+        bsec_physical_sensor_t::BSEC_INPUT_PROFILE_PART => "heater profile part",
+        _ => "unknown",
+    }
+}
 
-    return version;
+/// Whether BSEC fills in `bsec_output_t::accuracy` for this output. For all
+/// other outputs the field is always 0.
+pub fn reports_accuracy(sensor_id: u8) -> bool {
+    matches!(
+        sensor_id as u32,
+        bsec_virtual_sensor_t::BSEC_OUTPUT_IAQ
+            | bsec_virtual_sensor_t::BSEC_OUTPUT_STATIC_IAQ
+            | bsec_virtual_sensor_t::BSEC_OUTPUT_CO2_EQUIVALENT
+            | bsec_virtual_sensor_t::BSEC_OUTPUT_BREATH_VOC_EQUIVALENT
+            | bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_1
+            | bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_2
+            | bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_3
+            | bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_4
+    )
 }
 
-pub fn init(state: &mut State) {
-    unsafe {
-        state.result = bsec_init();
+pub fn mode_name(mode: f32) -> &'static str {
+    if mode == BSEC_SAMPLE_RATE_ULP as f32 {
+        "ulp"
+    } else if mode == BSEC_SAMPLE_RATE_LP as f32 {
+        "lp"
+    } else if mode == BSEC_SAMPLE_RATE_CONT as f32 {
+        "cont"
+    } else if mode == BSEC_SAMPLE_RATE_SCAN as f32 {
+        "scan"
+    } else {
+        "custom"
     }
+}
 
-    print_result(state, "Init");
+/// The name `parse_sample_rate` reads for `rate`, or the rate in Hz.
+pub fn sample_rate_name(rate: f32) -> String {
+    if rate == BSEC_SAMPLE_RATE_DISABLED as f32 {
+        return String::from("disabled");
+    }
+    match mode_name(rate) {
+        "custom" => rate.to_string(),
+        name => String::from(name),
+    }
 }
 
-pub fn update_subscription(state: &mut State, mode: f32) {
-    state.mode = mode;
+/// Temperature offset in degrees Celsius from the sensor heating itself,
+/// which grows with how often the gas heater runs.
+pub fn heat_source_offset(mode: f32) -> f32 {
+    if mode == BSEC_SAMPLE_RATE_ULP as f32 {
+        0.5f32
+    } else if mode == BSEC_SAMPLE_RATE_LP as f32 {
+        2f32
+    } else {
+        5f32
+    }
+}
 
-    state
-        .requested_virtual_sensors
-        .push(bsec_sensor_configuration_t {
-            sample_rate: mode,
-            sensor_id: bsec_virtual_sensor_t::BSEC_OUTPUT_SENSOR_HEAT_COMPENSATED_TEMPERATURE as u8,
-        });
+/// Parse a sample rate given either as one of the BSEC rate names or in Hz.
+pub fn parse_sample_rate(rate: &str) -> Result<f32, String> {
+    match rate {
+        "ulp" => Ok(BSEC_SAMPLE_RATE_ULP as f32),
+        "lp" => Ok(BSEC_SAMPLE_RATE_LP as f32),
+        "cont" => Ok(BSEC_SAMPLE_RATE_CONT as f32),
+        "scan" => Ok(BSEC_SAMPLE_RATE_SCAN as f32),
+        "disabled" => Ok(BSEC_SAMPLE_RATE_DISABLED as f32),
+        _ => rate
+            .parse::<f32>()
+            .map_err(|_| format!("invalid sample rate '{}'", rate)),
+    }
+}
 
-    state
-        .requested_virtual_sensors
-        .push(bsec_sensor_configuration_t {
-            sample_rate: mode,
-            sensor_id: bsec_virtual_sensor_t::BSEC_OUTPUT_SENSOR_HEAT_COMPENSATED_HUMIDITY as u8,
-        });
+/// Parse a comma separated list of outputs, each optionally followed by
+/// `:<rate>`, e.g. `iaq,co2_equivalent,raw_gas:ulp`. Outputs without a rate
+/// use `default_rate`.
+pub fn parse_outputs(
+    spec: &str,
+    default_rate: f32,
+) -> Result<Vec<bsec_sensor_configuration_t>, String> {
+    let mut outputs: Vec<bsec_sensor_configuration_t> = Vec::new();
+
+    for item in spec.split(',').map(|item| item.trim()) {
+        if item.is_empty() {
+            continue;
+        }
 
-    state
-        .requested_virtual_sensors
-        .push(bsec_sensor_configuration_t {
-            sample_rate: mode,
-            sensor_id: bsec_virtual_sensor_t::BSEC_OUTPUT_STABILIZATION_STATUS as u8,
-        });
+        let (name, sample_rate) = match item.split_once(':') {
+            Some((name, rate)) => (name.trim(), parse_sample_rate(rate.trim())?),
+            None => (item, default_rate),
+        };
+
+        let sensor_id = VIRTUAL_SENSORS
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, id)| *id as u8)
+            .ok_or_else(|| {
+                let known: Vec<&str> = VIRTUAL_SENSORS.iter().map(|(name, _)| *name).collect();
+                format!(
+                    "unknown output '{}', expected one of {}",
+                    name,
+                    known.join(", ")
+                )
+            })?;
+
+        if outputs.iter().any(|output| output.sensor_id == sensor_id) {
+            return Err(format!("output '{}' is listed twice", name));
+        }
 
-    state
-        .requested_virtual_sensors
-        .push(bsec_sensor_configuration_t {
-            sample_rate: mode,
-            sensor_id: bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_PRESSURE as u8,
+        outputs.push(bsec_sensor_configuration_t {
+            sample_rate,
+            sensor_id,
         });
+    }
 
-    state
-        .requested_virtual_sensors
-        .push(bsec_sensor_configuration_t {
-            sample_rate: mode,
-            sensor_id: bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_GAS as u8,
-        });
+    if outputs.is_empty() {
+        return Err(String::from("no outputs listed"));
+    }
 
-    if mode == BSEC_SAMPLE_RATE_LP as f32 {
-        state
-            .requested_virtual_sensors
-            .push(bsec_sensor_configuration_t {
-                sample_rate: mode,
-                sensor_id: bsec_virtual_sensor_t::BSEC_OUTPUT_STATIC_IAQ as u8,
-            });
-        state
-            .requested_virtual_sensors
-            .push(bsec_sensor_configuration_t {
-                sample_rate: mode,
-                sensor_id: bsec_virtual_sensor_t::BSEC_OUTPUT_BREATH_VOC_EQUIVALENT as u8,
-            });
+    Ok(outputs)
+}
+
+/// The outputs subscribed to when `BSEC_OUTPUTS` is not set.
+pub fn default_outputs(mode: f32) -> Vec<bsec_sensor_configuration_t> {
+    let mut sensor_ids = vec![
+        bsec_virtual_sensor_t::BSEC_OUTPUT_SENSOR_HEAT_COMPENSATED_TEMPERATURE,
+        bsec_virtual_sensor_t::BSEC_OUTPUT_SENSOR_HEAT_COMPENSATED_HUMIDITY,
+        bsec_virtual_sensor_t::BSEC_OUTPUT_STABILIZATION_STATUS,
+        bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_PRESSURE,
+        bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_GAS,
+    ];
+
+    // IAQ is only available in the low power modes
+    if mode == BSEC_SAMPLE_RATE_LP as f32 || mode == BSEC_SAMPLE_RATE_ULP as f32 {
+        sensor_ids.push(bsec_virtual_sensor_t::BSEC_OUTPUT_STATIC_IAQ);
+        sensor_ids.push(bsec_virtual_sensor_t::BSEC_OUTPUT_BREATH_VOC_EQUIVALENT);
     } else if mode == BSEC_SAMPLE_RATE_SCAN as f32 {
-        state
-            .requested_virtual_sensors
-            .push(bsec_sensor_configuration_t {
-                sample_rate: mode,
-                sensor_id: bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_1 as u8,
-            });
+        sensor_ids.push(bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_1);
+        sensor_ids.push(bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_2);
+        sensor_ids.push(bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_3);
+        sensor_ids.push(bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_4);
+    }
 
-        state
-            .requested_virtual_sensors
-            .push(bsec_sensor_configuration_t {
-                sample_rate: mode,
-                sensor_id: bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_2 as u8,
-            });
+    sensor_ids
+        .into_iter()
+        .map(|sensor_id| bsec_sensor_configuration_t {
+            sample_rate: mode,
+            sensor_id: sensor_id as u8,
+        })
+        .collect()
+}
+
+/// Read a configuration blob as shipped by Bosch (`bsec_iaq.config`) or
+/// exported by BME AI Studio. Files prefixed with the 4 byte little endian
+/// blob length are accepted as well.
+pub fn load_configuration(path: &Path) -> Result<Vec<u8>, io::Error> {
+    let mut blob = fs::read(path)?;
+
+    if blob.len() == BSEC_MAX_PROPERTY_BLOB_SIZE as usize + 4 {
+        let header = u32::from_le_bytes([blob[0], blob[1], blob[2], blob[3]]);
+        if header == BSEC_MAX_PROPERTY_BLOB_SIZE {
+            blob.drain(0..4);
+        }
     }
 
-    for _ in 0..BSEC_MAX_PHYSICAL_SENSOR {
-        state
-            .required_sensor_settings
-            .push(bsec_sensor_configuration_t {
-                sample_rate: 0f32,
-                sensor_id: 1,
-            })
+    if blob.len() != BSEC_MAX_PROPERTY_BLOB_SIZE as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "BSEC config {} is {} bytes, this BSEC version expects {} bytes",
+                path.display(),
+                blob.len(),
+                BSEC_MAX_PROPERTY_BLOB_SIZE
+            ),
+        ));
     }
 
-    state.n_required_sensor_settings = BSEC_MAX_PHYSICAL_SENSOR as u8;
+    Ok(blob)
+}
 
-    unsafe {
-        state.result = bsec_update_subscription(
-            state.requested_virtual_sensors.as_mut_ptr(),
-            state.requested_virtual_sensors.len() as u8,
-            state.required_sensor_settings.as_mut_ptr(),
-            &mut state.n_required_sensor_settings as *mut u8,
-        );
+/// Handle to one BSEC instance.
+///
+/// Every instance has its own memory allocated from
+/// `bsec_get_instance_size_m`, so several sensors can be processed
+/// independently in the same process. Every call stores the raw return code
+/// in `last_result`, and turns anything other than `BSEC_OK` into a
+/// `BsecError`. Only `init` creates one, as every call needs the instance
+/// memory.
+pub struct Bsec {
+    /// Instance memory, as `u64` to keep it 8 byte aligned.
+    instance: Vec<u64>,
+    last_result: i32,
+    pub mode: f32,
+    pub requested_virtual_sensors: Vec<bsec_sensor_configuration_t>,
+    pub required_sensor_settings: Vec<bsec_sensor_configuration_t>,
+    pub n_required_sensor_settings: u8,
+    pub sensor_settings: bsec_bme_settings_t,
+    /// Heat source offset to use instead of `heat_source_offset(mode)`.
+    pub temperature_offset: Option<f32>,
+    /// As reported by `get_version`, saved along with the state.
+    pub version: bsec_version_t,
+}
+
+impl Bsec {
+    pub fn init() -> Result<Bsec, BsecError> {
+        let instance_size = unsafe { bsec_get_instance_size_m() } as usize;
+
+        let mut bsec = Bsec {
+            instance: vec![0; (instance_size + 7) / 8],
+            last_result: 0,
+            mode: 0.0,
+            requested_virtual_sensors: Vec::new(),
+            required_sensor_settings: Vec::new(),
+            n_required_sensor_settings: 0,
+            sensor_settings: bsec_bme_settings_t::default(),
+            temperature_offset: None,
+            version: bsec_version_t::default(),
+        };
+
+        let result = unsafe { bsec_init_m(bsec.instance()) };
+        bsec.check(result, "Init")?;
+
+        Ok(bsec)
     }
 
-    print_result(state, "Update Subscription");
-    debug!(
-        "Required Sensor Settings: {:#?}",
-        state.required_sensor_settings
-    );
-}
+    pub fn get_version(&mut self) -> Result<bsec_version_t, BsecError> {
+        let mut version = bsec_version_t {
+            major: 0,
+            minor: 0,
+            major_bugfix: 0,
+            minor_bugfix: 0,
+        };
+
+        let result =
+            unsafe { bsec_get_version_m(self.instance(), &mut version as *mut bsec_version_t) };
+        self.check(result, "Get Version")?;
 
-pub fn get_sensor_config(state: &mut State, timestamp: i64) {
-    unsafe {
-        state.result = bsec_sensor_control(
-            timestamp,
-            &mut state.sensor_settings as *mut bsec_bme_settings_t,
+        self.version = version;
+
+        info!(
+            "BSEC Version: {}.{}.{}",
+            version.major, version.minor, version.major_bugfix
         );
+
+        Ok(version)
     }
 
-    print_result(state, "Sensor Control");
-    debug!("Sensor Settings: {:?}", state.sensor_settings);
-}
+    fn instance(&mut self) -> *mut c_void {
+        self.instance.as_mut_ptr() as *mut c_void
+    }
 
-pub fn process_data(
-    state: &State,
-    measure_results: &SensorData,
-    timestamp: i64,
-) -> Vec<bsec_input_t> {
-    let mut sensor_inputs = Vec::new();
+    pub fn last_result(&self) -> i32 {
+        self.last_result
+    }
 
-    let temp_compensation = if state.mode == BSEC_SAMPLE_RATE_LP as f32 {
-        2f32
-    } else {
-        5f32
-    };
+    pub fn update_subscription(
+        &mut self,
+        mode: f32,
+        outputs: &[bsec_sensor_configuration_t],
+    ) -> Result<(), BsecError> {
+        self.mode = mode;
+        self.requested_virtual_sensors = outputs.to_vec();
+
+        for output in &self.requested_virtual_sensors {
+            info!(
+                "Requesting output {} at {} Hz",
+                virtual_sensor_name(output.sensor_id),
+                output.sample_rate
+            );
+        }
 
-    sensor_inputs.push(bsec_input_t {
-        time_stamp: timestamp,
-        signal: temp_compensation,
-        signal_dimensions: 1,
-        sensor_id: bsec_physical_sensor_t::BSEC_INPUT_HEATSOURCE as u8,
-    });
+        let mut outputs = self.requested_virtual_sensors.clone();
+        self.subscribe(&mut outputs, "Update Subscription")?;
 
-    // Pressure
-    if state.sensor_settings.process_data & 0b1 == 0b1 {
-        sensor_inputs.push(bsec_input_t {
-            time_stamp: timestamp,
-            signal: measure_results.pressure,
-            signal_dimensions: 1,
-            sensor_id: bsec_physical_sensor_t::BSEC_INPUT_PRESSURE as u8,
-        });
+        for setting in &self.required_sensor_settings {
+            info!(
+                "Required sensor setting: {} at {} Hz",
+                physical_sensor_name(setting.sensor_id),
+                setting.sample_rate
+            );
+        }
+
+        Ok(())
     }
 
-    // Humidity
-    if state.sensor_settings.process_data & 0b10 == 0b10 {
-        sensor_inputs.push(bsec_input_t {
-            time_stamp: timestamp,
-            signal: measure_results.humidity,
-            signal_dimensions: 1,
-            sensor_id: bsec_physical_sensor_t::BSEC_INPUT_HUMIDITY as u8,
-        });
+    /// Ask BSEC for an extra measurement outside the regular ULP schedule by
+    /// resubscribing the ULP outputs at `BSEC_SAMPLE_RATE_ULP_MEASUREMENT_ON_DEMAND`.
+    /// BSEC schedules it at the next `get_sensor_config`, and rejects it with
+    /// a warning outside ULP mode or when requested too often.
+    pub fn request_measurement_on_demand(&mut self) -> Result<(), BsecError> {
+        let mut outputs: Vec<bsec_sensor_configuration_t> = self
+            .requested_virtual_sensors
+            .iter()
+            .filter(|output| output.sample_rate == BSEC_SAMPLE_RATE_ULP as f32)
+            .map(|output| bsec_sensor_configuration_t {
+                sample_rate: BSEC_SAMPLE_RATE_ULP_MEASUREMENT_ON_DEMAND as f32,
+                sensor_id: output.sensor_id,
+            })
+            .collect();
+
+        if outputs.is_empty() {
+            warn!("No outputs subscribed in ULP mode, ignoring on-demand measurement.");
+            return Err(BsecError::SubscriptionModeInNonUlp);
+        }
+
+        self.subscribe(&mut outputs, "Measurement On Demand")
     }
 
-    // Temperature
-    if state.sensor_settings.process_data & 0b100 == 0b100 {
-        sensor_inputs.push(bsec_input_t {
-            time_stamp: timestamp,
-            signal: measure_results.temperature,
-            signal_dimensions: 1,
-            sensor_id: bsec_physical_sensor_t::BSEC_INPUT_TEMPERATURE as u8,
-        });
+    fn subscribe(
+        &mut self,
+        outputs: &mut [bsec_sensor_configuration_t],
+        op_name: &str,
+    ) -> Result<(), BsecError> {
+        self.required_sensor_settings.clear();
+
+        for _ in 0..BSEC_MAX_PHYSICAL_SENSOR {
+            self.required_sensor_settings
+                .push(bsec_sensor_configuration_t {
+                    sample_rate: 0f32,
+                    sensor_id: 1,
+                })
+        }
+
+        self.n_required_sensor_settings = BSEC_MAX_PHYSICAL_SENSOR as u8;
+
+        let result = unsafe {
+            bsec_update_subscription_m(
+                self.instance(),
+                outputs.as_mut_ptr(),
+                outputs.len() as u8,
+                self.required_sensor_settings.as_mut_ptr(),
+                &mut self.n_required_sensor_settings as *mut u8,
+            )
+        };
+        self.check(result, op_name)?;
+
+        self.required_sensor_settings
+            .truncate(self.n_required_sensor_settings as usize);
+
+        Ok(())
     }
 
-    // Gas
-    if state.sensor_settings.process_data & 0b1000 == 0b1000 {
-        sensor_inputs.push(bsec_input_t {
-            time_stamp: timestamp,
-            signal: measure_results.gas_resistance,
-            signal_dimensions: 1,
-            sensor_id: bsec_physical_sensor_t::BSEC_INPUT_GASRESISTOR as u8,
-        });
+    /// Whether the last sensor settings ask for parallel mode
+    /// (`BME68X_PARALLEL_MODE`), which BSEC uses for gas scanning.
+    pub fn parallel_mode(&self) -> bool {
+        self.sensor_settings.op_mode == 2
+    }
+
+    /// Ask BSEC how to configure the sensor for the measurement at
+    /// `timestamp`. The settings are stored in `sensor_settings` even when a
+    /// warning such as `CallTimingViolation` is returned.
+    pub fn get_sensor_config(&mut self, timestamp: i64) -> Result<(), BsecError> {
+        let result = unsafe {
+            bsec_sensor_control_m(
+                self.instance(),
+                timestamp,
+                &mut self.sensor_settings as *mut bsec_bme_settings_t,
+            )
+        };
+
+        debug!("Sensor Settings: {:?}", self.sensor_settings);
+
+        self.check(result, "Sensor Control")
+    }
+
+    pub fn process_data(&self, measure_results: &RawReading) -> Vec<bsec_input_t> {
+        let timestamp = measure_results.timestamp;
+        let mut sensor_inputs = Vec::new();
 
         sensor_inputs.push(bsec_input_t {
             time_stamp: timestamp,
-            signal: measure_results.gas_index.into(),
+            signal: self
+                .temperature_offset
+                .unwrap_or_else(|| heat_source_offset(self.mode)),
             signal_dimensions: 1,
-            sensor_id: bsec_physical_sensor_t::BSEC_INPUT_PROFILE_PART as u8,
+            sensor_id: bsec_physical_sensor_t::BSEC_INPUT_HEATSOURCE as u8,
         });
-    }
 
-    sensor_inputs
-}
+        // Pressure
+        if self.sensor_settings.process_data & 0b1 == 0b1 {
+            sensor_inputs.push(bsec_input_t {
+                time_stamp: timestamp,
+                signal: measure_results.pressure,
+                signal_dimensions: 1,
+                sensor_id: bsec_physical_sensor_t::BSEC_INPUT_PRESSURE as u8,
+            });
+        }
 
-pub fn do_steps(state: &mut State, inputs: &Vec<bsec_input_t>) -> Vec<bsec_output_t> {
-    let mut sensor_outputs = Vec::new();
-    let mut n_sensor_outputs: u8 = state.requested_virtual_sensors.len() as u8;
+        // Humidity
+        if self.sensor_settings.process_data & 0b10 == 0b10 {
+            sensor_inputs.push(bsec_input_t {
+                time_stamp: timestamp,
+                signal: measure_results.humidity,
+                signal_dimensions: 1,
+                sensor_id: bsec_physical_sensor_t::BSEC_INPUT_HUMIDITY as u8,
+            });
+        }
 
-    for _ in 0..state.requested_virtual_sensors.len() {
-        sensor_outputs.push(bsec_output_t::default());
-    }
+        // Temperature
+        if self.sensor_settings.process_data & 0b100 == 0b100 {
+            sensor_inputs.push(bsec_input_t {
+                time_stamp: timestamp,
+                signal: measure_results.temperature,
+                signal_dimensions: 1,
+                sensor_id: bsec_physical_sensor_t::BSEC_INPUT_TEMPERATURE as u8,
+            });
+        }
 
-    unsafe {
-        state.result = bsec_do_steps(
-            inputs.as_ptr(),
-            inputs.len() as u8,
-            sensor_outputs.as_mut_ptr(),
-            &mut n_sensor_outputs as *mut u8,
-        );
+        // Gas
+        if self.sensor_settings.process_data & 0b1000 == 0b1000 {
+            sensor_inputs.push(bsec_input_t {
+                time_stamp: timestamp,
+                signal: measure_results.gas_resistance,
+                signal_dimensions: 1,
+                sensor_id: bsec_physical_sensor_t::BSEC_INPUT_GASRESISTOR as u8,
+            });
+
+            sensor_inputs.push(bsec_input_t {
+                time_stamp: timestamp,
+                signal: measure_results.gas_index.into(),
+                signal_dimensions: 1,
+                sensor_id: bsec_physical_sensor_t::BSEC_INPUT_PROFILE_PART as u8,
+            });
+        }
+
+        sensor_inputs
     }
 
-    print_result(state, "Do Steps");
+    /// Run the BSEC algorithms on one set of inputs. Warnings are logged and
+    /// the outputs returned anyway, since BSEC still computed them.
+    pub fn do_steps(&mut self, inputs: &[bsec_input_t]) -> Result<Vec<bsec_output_t>, BsecError> {
+        let mut sensor_outputs = Vec::new();
+        let mut n_sensor_outputs: u8 = self.requested_virtual_sensors.len() as u8;
 
-    for i in 0..n_sensor_outputs as usize {
-        let output = sensor_outputs[i];
-        match output.sensor_id as u32 {
-            bsec_virtual_sensor_t::BSEC_OUTPUT_STATIC_IAQ => {
-                debug!("Static IAQ: {}", output.signal);
-            }
-            bsec_virtual_sensor_t::BSEC_OUTPUT_STABILIZATION_STATUS => {
-                debug!("Gas sensor stable: {}", output.signal == 1.0);
-            }
-            bsec_virtual_sensor_t::BSEC_OUTPUT_SENSOR_HEAT_COMPENSATED_TEMPERATURE => {
-                debug!("Temperature: {}", output.signal);
-            }
-            bsec_virtual_sensor_t::BSEC_OUTPUT_SENSOR_HEAT_COMPENSATED_HUMIDITY => {
-                debug!("Humidity: {}", output.signal);
-            }
-            bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_PRESSURE => {
-                debug!("Air Pressure: {}", output.signal);
-            }
-            bsec_virtual_sensor_t::BSEC_OUTPUT_BREATH_VOC_EQUIVALENT => {
-                debug!("Breath VOC [ppm]: {}", output.signal);
-            }
-            bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_GAS_INDEX => {
-                debug!("Gas Index: {}", output.signal);
-            }
-            bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_GAS => {
-                debug!("Gas sensor resistance: {}", output.signal);
-            }
-            _ => {
-                debug!("{:?}", output);
+        for _ in 0..self.requested_virtual_sensors.len() {
+            sensor_outputs.push(bsec_output_t::default());
+        }
+
+        let result = unsafe {
+            bsec_do_steps_m(
+                self.instance(),
+                inputs.as_ptr(),
+                inputs.len() as u8,
+                sensor_outputs.as_mut_ptr(),
+                &mut n_sensor_outputs as *mut u8,
+            )
+        };
+
+        match self.check(result, "Do Steps") {
+            Err(e) if !e.is_warning() => return Err(e),
+            _ => {}
+        }
+
+        for output in sensor_outputs.iter().take(n_sensor_outputs as usize) {
+            match output.sensor_id as u32 {
+                bsec_virtual_sensor_t::BSEC_OUTPUT_STATIC_IAQ => {
+                    debug!("Static IAQ: {}", output.signal);
+                }
+                bsec_virtual_sensor_t::BSEC_OUTPUT_STABILIZATION_STATUS => {
+                    debug!("Gas sensor stable: {}", output.signal == 1.0);
+                }
+                bsec_virtual_sensor_t::BSEC_OUTPUT_SENSOR_HEAT_COMPENSATED_TEMPERATURE => {
+                    debug!("Temperature: {}", output.signal);
+                }
+                bsec_virtual_sensor_t::BSEC_OUTPUT_SENSOR_HEAT_COMPENSATED_HUMIDITY => {
+                    debug!("Humidity: {}", output.signal);
+                }
+                bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_PRESSURE => {
+                    debug!("Air Pressure: {}", output.signal);
+                }
+                bsec_virtual_sensor_t::BSEC_OUTPUT_BREATH_VOC_EQUIVALENT => {
+                    debug!("Breath VOC [ppm]: {}", output.signal);
+                }
+                bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_GAS_INDEX => {
+                    debug!("Gas Index: {}", output.signal);
+                }
+                bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_GAS => {
+                    debug!("Gas sensor resistance: {}", output.signal);
+                }
+                _ => {
+                    debug!("{:?}", output);
+                }
             }
         }
+
+        sensor_outputs.truncate(n_sensor_outputs as usize);
+
+        Ok(sensor_outputs)
     }
 
-    sensor_outputs[0..n_sensor_outputs as usize].into()
-}
+    pub fn set_configuration(&mut self, serialized_settings: &[u8]) -> Result<(), BsecError> {
+        let mut work_buffer: Vec<u8> = vec![0; BSEC_MAX_WORKBUFFER_SIZE as usize];
 
-pub fn get_bsec_state() -> Vec<u8> {
-    let mut serialized_state: Vec<u8> = vec![0; BSEC_MAX_STATE_BLOB_SIZE as usize];
-    let mut work_buffer_state: Vec<u8> = vec![0; BSEC_MAX_WORKBUFFER_SIZE as usize];
-    let mut n_serialized_state: u32 = 0;
-
-    unsafe {
-        bsec_get_state(
-            0,
-            serialized_state.as_mut_ptr(),
-            BSEC_MAX_STATE_BLOB_SIZE,
-            work_buffer_state.as_mut_ptr(),
-            BSEC_MAX_WORKBUFFER_SIZE,
-            &mut n_serialized_state as *mut u32,
-        );
+        let result = unsafe {
+            bsec_set_configuration_m(
+                self.instance(),
+                serialized_settings.as_ptr(),
+                serialized_settings.len() as u32,
+                work_buffer.as_mut_ptr(),
+                BSEC_MAX_WORKBUFFER_SIZE,
+            )
+        };
+
+        self.check(result, "Set Configuration")
     }
 
-    serialized_state[0..n_serialized_state as usize].into()
-}
+    pub fn get_state(&mut self) -> Result<Vec<u8>, BsecError> {
+        let mut serialized_state: Vec<u8> = vec![0; BSEC_MAX_STATE_BLOB_SIZE as usize];
+        let mut work_buffer_state: Vec<u8> = vec![0; BSEC_MAX_WORKBUFFER_SIZE as usize];
+        let mut n_serialized_state: u32 = 0;
+
+        let result = unsafe {
+            bsec_get_state_m(
+                self.instance(),
+                0,
+                serialized_state.as_mut_ptr(),
+                BSEC_MAX_STATE_BLOB_SIZE,
+                work_buffer_state.as_mut_ptr(),
+                BSEC_MAX_WORKBUFFER_SIZE,
+                &mut n_serialized_state as *mut u32,
+            )
+        };
+        self.check(result, "Get State")?;
+
+        serialized_state.truncate(n_serialized_state as usize);
+
+        Ok(serialized_state)
+    }
 
-pub fn set_bsec_state(serialized_state: Vec<u8>) {
-    let mut work_buffer_state: Vec<u8> = vec![0; BSEC_MAX_WORKBUFFER_SIZE as usize];
+    pub fn set_state(&mut self, serialized_state: &[u8]) -> Result<(), BsecError> {
+        let mut work_buffer_state: Vec<u8> = vec![0; BSEC_MAX_WORKBUFFER_SIZE as usize];
 
-    unsafe {
-        bsec_set_state(
-            serialized_state.as_ptr(),
-            serialized_state.len() as u32,
-            work_buffer_state.as_mut_ptr(),
-            BSEC_MAX_WORKBUFFER_SIZE,
-        );
+        let result = unsafe {
+            bsec_set_state_m(
+                self.instance(),
+                serialized_state.as_ptr(),
+                serialized_state.len() as u32,
+                work_buffer_state.as_mut_ptr(),
+                BSEC_MAX_WORKBUFFER_SIZE,
+            )
+        };
+
+        self.check(result, "Set State")
     }
-}
 
-fn print_result(state: &State, op_name: &str) {
-    if state.result == 0 {
-        info!("BSEC {}: OK", op_name);
-    } else {
-        error!("BSEC {}: Error {}", op_name, state.result);
+    fn check(
+        &mut self,
+        result: bsec_library_return_t::Type,
+        op_name: &str,
+    ) -> Result<(), BsecError> {
+        self.last_result = result;
+
+        if result == bsec_library_return_t::BSEC_OK {
+            info!("BSEC {}: OK", op_name);
+            return Ok(());
+        }
+
+        let err = BsecError::from_code(result);
+        if err.is_warning() {
+            warn!(bsec_return_code = result; "BSEC {}: {}", op_name, err);
+        } else {
+            error!(bsec_return_code = result; "BSEC {}: {}", op_name, err);
+        }
+
+        Err(err)
     }
 }
@@ -0,0 +1,435 @@
+use crate::bme;
+use crate::bsec::Bsec;
+use crate::config::Config;
+use crate::control;
+use crate::file::escape;
+use crate::mock::MockI2c;
+use crate::recording::RawReading;
+use crate::sensor;
+use crate::sink::SensorId;
+use crate::state;
+use chrono::{DateTime, Local, TimeZone};
+use std::ffi::OsStr;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+/// Config file read from the working directory if it exists and no other is
+/// given.
+const DEFAULT_CONFIG_FILE: &str = "bme-sensors.toml";
+
+pub const USAGE: &str = "Usage: bme-sensors [--config <file>] [--check-config] [<command>]
+
+Commands:
+  run                     Read the sensors and send the readings to the
+                          outputs, the default.
+  scan                    List the i2c buses and the BME68x found on them.
+  read                    Take one measurement of every sensor without BSEC
+                          and print it as JSON.
+  bsec-version            Print the version of the BSEC library.
+  reload                  Make the running program read its config again,
+                          through CONTROL_SOCKET, like SIGHUP.
+  measure                 Request a measurement on demand in ULP mode
+                          through CONTROL_SOCKET, like SIGUSR1.
+  state show [<file>...]  Show the BSEC state files and whether BSEC accepts
+                          them, all files next to the state file by default.
+  state backup [<file>...]
+                          Copy the state files to <file>.<date and time>.
+  state reset [<file>...] Back up and remove the state files, so BSEC starts
+                          uncalibrated. Stop the running program first, as it
+                          saves its state on exit.
+
+Options:
+  -c, --config <file>  Read settings from this TOML file, by default
+                       bme-sensors.toml if it exists. Environment variables
+                       and .env take precedence over it.
+      --check-config   Print the effective configuration and exit.
+  -h, --help           Print this help.";
+
+/// What to do with the BSEC state files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateAction {
+    Show,
+    Backup,
+    Reset,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Scan,
+    Read,
+    BsecVersion,
+    State(StateAction, Vec<PathBuf>),
+    Reload,
+    Measure,
+}
+
+/// Command line arguments.
+pub struct Args {
+    pub config_file: Option<PathBuf>,
+    pub check_config: bool,
+    pub command: Command,
+}
+
+pub fn parse_args() -> Result<Args, String> {
+    let mut config_file = None;
+    let mut check_config = false;
+    let mut words = Vec::new();
+
+    let mut argv = env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "-c" | "--config" => {
+                let file = argv.next().ok_or("--config needs a file")?;
+                config_file = Some(PathBuf::from(file));
+            }
+            "--check-config" => check_config = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => match arg.strip_prefix("--config=") {
+                Some(file) => config_file = Some(PathBuf::from(file)),
+                None if arg.starts_with('-') => {
+                    return Err(format!("Unknown option '{}'", arg));
+                }
+                None => words.push(arg),
+            },
+        }
+    }
+
+    let command = match words.first().map(String::as_str) {
+        None | Some("run") => Command::Run,
+        Some("scan") => Command::Scan,
+        Some("read") => Command::Read,
+        Some("bsec-version") => Command::BsecVersion,
+        Some("reload") => Command::Reload,
+        Some("measure") => Command::Measure,
+        Some("state") => {
+            let action = match words.get(1).map(String::as_str) {
+                Some("show") => StateAction::Show,
+                Some("backup") => StateAction::Backup,
+                Some("reset") => StateAction::Reset,
+                Some(action) => {
+                    return Err(format!(
+                        "Unknown state action '{}', expected show, backup or reset",
+                        action
+                    ))
+                }
+                None => return Err(String::from("state needs show, backup or reset")),
+            };
+            let files = words.iter().skip(2).map(PathBuf::from).collect();
+            Command::State(action, files)
+        }
+        Some(command) => return Err(format!("Unknown command '{}'", command)),
+    };
+
+    let takes_arguments = matches!(command, Command::State(..));
+    if !takes_arguments && words.len() > 1 {
+        return Err(format!("Unexpected argument '{}'", words[1]));
+    }
+
+    if config_file.is_none() && Path::new(DEFAULT_CONFIG_FILE).exists() {
+        config_file = Some(PathBuf::from(DEFAULT_CONFIG_FILE));
+    }
+
+    Ok(Args {
+        config_file,
+        check_config,
+        command,
+    })
+}
+
+/// List every i2c bus with what answers at the two BME68x addresses.
+pub fn scan(config: &Config) -> Result<(), Error> {
+    if config.mock {
+        let mut mock = MockI2c::default();
+        let (chip_id, variant_id) = bme::read_id(&mut mock).unwrap_or_default();
+        println!("mock");
+        println!(
+            "  {:#04x}: {}, chip id {:#04x}, variant id {:#04x}",
+            bme68x_rust::I2C_ADDR_HIGH,
+            bme::variant_name(variant_id),
+            chip_id,
+            variant_id
+        );
+        return Ok(());
+    }
+
+    let buses = bme::buses();
+    if buses.is_empty() {
+        println!("No i2c buses found, is i2c enabled?");
+        return Ok(());
+    }
+
+    for path in buses {
+        println!("{}", path.display());
+
+        for address in bme::ADDRESSES {
+            let mut driver = match bme::create_device(&path, address) {
+                Some(driver) => driver,
+                None => {
+                    println!("  {:#04x}: cannot be opened", address);
+                    continue;
+                }
+            };
+
+            let (chip_id, variant_id) = match bme::read_id(&mut driver) {
+                Ok(id) => id,
+                Err(_) => {
+                    println!("  {:#04x}: no response", address);
+                    continue;
+                }
+            };
+
+            if chip_id != bme::CHIP_ID {
+                println!("  {:#04x}: not a BME68x, chip id {:#04x}", address, chip_id);
+                continue;
+            }
+
+            let state = match bme::init(driver) {
+                Some(_) => "ready",
+                None => "failed to initialize",
+            };
+            println!(
+                "  {:#04x}: {}, chip id {:#04x}, variant id {:#04x}, {}",
+                address,
+                bme::variant_name(variant_id),
+                chip_id,
+                variant_id,
+                state
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Print one forced mode measurement of every sensor as a JSON line.
+pub fn read(config: &Config) -> Result<(), Error> {
+    if config.mock {
+        let mut device = bme::init(MockI2c::default())
+            .ok_or_else(|| Error::new(ErrorKind::Other, "Cannot initialize mock Device."))?;
+        let reading = bme::measure_once(&mut device)?;
+        println!("{}", reading_json(&sensor::mock_id(), &reading));
+        return Ok(());
+    }
+
+    let found = sensor::find_sensors(config);
+    if found.is_empty() {
+        return Err(Error::new(ErrorKind::NotFound, "Cannot find i2c Device."));
+    }
+
+    let mut result = Ok(());
+
+    for mut found in found {
+        match bme::measure_once(&mut found.device) {
+            Ok(reading) => println!("{}", reading_json(&found.id, &reading)),
+            Err(e) => {
+                eprintln!("Cannot read {}: {}", found.id.name, e);
+                result = Err(e);
+            }
+        }
+    }
+
+    result
+}
+
+/// e.g. `{"timestamp":1700000000000000000,"sensor":"study","bus":"i2c-1","address":119,"temperature":22.5,"humidity":45,"pressure":101325,"gas_resistance":50000,"gas_valid":true,"heater_stable":true}`
+fn reading_json(id: &SensorId, reading: &RawReading) -> String {
+    let mut json = format!(
+        "{{\"timestamp\":{},\"sensor\":\"{}\",\"bus\":\"{}\",\"address\":{}",
+        reading.timestamp,
+        escape(&id.name),
+        escape(&id.bus),
+        id.address
+    );
+    if let Some(location) = id.location.as_ref() {
+        json.push_str(&format!(",\"location\":\"{}\"", escape(location)));
+    }
+
+    for (name, value) in [
+        ("temperature", reading.temperature),
+        ("humidity", reading.humidity),
+        ("pressure", reading.pressure),
+        ("gas_resistance", reading.gas_resistance),
+    ] {
+        // JSON has no NaN or infinity
+        if value.is_finite() {
+            json.push_str(&format!(",\"{}\":{}", name, value));
+        } else {
+            json.push_str(&format!(",\"{}\":null", name));
+        }
+    }
+
+    json.push_str(&format!(
+        ",\"gas_valid\":{},\"heater_stable\":{}}}",
+        reading.status & bme::GAS_VALID != 0,
+        reading.status & bme::HEAT_STAB != 0
+    ));
+
+    json
+}
+
+pub fn bsec_version() -> Result<(), Error> {
+    let version = Bsec::init()?.get_version()?;
+    println!("{}", state::version_name(state::version_bytes(&version)));
+    Ok(())
+}
+
+/// Send a command to the running program over its control socket.
+pub fn control(config: &Config, command: &str) -> Result<(), Error> {
+    let path = config.control_socket.as_ref().ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            "CONTROL_SOCKET is not set, send a signal to the running program instead",
+        )
+    })?;
+
+    control::send(path, command)
+}
+
+/// Show, back up or reset the given state files, or all state files next to
+/// `config.state_file`.
+pub fn state(config: &Config, action: StateAction, files: &[PathBuf]) -> Result<(), Error> {
+    let files = if files.is_empty() {
+        state_files(&config.state_file)?
+    } else {
+        files.to_vec()
+    };
+
+    if files.is_empty() {
+        println!("No BSEC state found at {}", config.state_file.display());
+        return Ok(());
+    }
+
+    let backups = config.state_save.backups;
+
+    for file in files {
+        state_action(action, &file, backups)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", file.display(), e)))?;
+    }
+
+    Ok(())
+}
+
+fn state_action(action: StateAction, file: &Path, backups: usize) -> Result<(), Error> {
+    match action {
+        StateAction::Show => {
+            for file in state::candidates(file, backups) {
+                show_state(&file)?;
+            }
+        }
+        StateAction::Backup => {
+            let backup = backup_state(file)?;
+            println!("Copied {} to {}", file.display(), backup.display());
+        }
+        StateAction::Reset => {
+            let backup = backup_state(file)?;
+            fs::remove_file(file)?;
+            println!("Removed {}, backup in {}", file.display(), backup.display());
+
+            // Otherwise the next start falls back to a backup
+            for older in state::candidates(file, backups).into_iter().skip(1) {
+                fs::remove_file(&older)?;
+                println!("Removed {}", older.display());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The state file and the state files of named sensors next to it, see
+/// `sensor::file_for_sensor`.
+fn state_files(state_file: &Path) -> Result<Vec<PathBuf>, Error> {
+    let dir = match state_file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let stem = state_file
+        .file_stem()
+        .map(|stem| format!("{}_", stem.to_string_lossy()))
+        .unwrap_or_default();
+
+    let mut files = Vec::new();
+
+    let entries =
+        fs::read_dir(dir).map_err(|e| Error::new(e.kind(), format!("{}: {}", dir.display(), e)))?;
+
+    for entry in entries {
+        // Keep the directory as given, without a leading ./
+        let path = state_file.with_file_name(entry?.file_name());
+        let name = path
+            .file_name()
+            .map(OsStr::to_string_lossy)
+            .unwrap_or_default();
+        let is_state_file = path == state_file
+            || (name.starts_with(&stem) && path.extension() == state_file.extension());
+        if is_state_file && path.is_file() {
+            files.push(path);
+        }
+    }
+
+    files.sort();
+
+    Ok(files)
+}
+
+fn show_state(file: &Path) -> Result<(), Error> {
+    let saved = match state::read(file) {
+        Ok(saved) => saved,
+        Err(e) if e.kind() == ErrorKind::InvalidData => {
+            println!("{}: damaged, {}", file.display(), e);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    // Files from earlier versions have no header, their age is the file's
+    let saved_at: DateTime<Local> = match saved
+        .saved
+        .and_then(|secs| Local.timestamp_opt(secs, 0).single())
+    {
+        Some(saved_at) => saved_at,
+        None => fs::metadata(file)?.modified()?.into(),
+    };
+    let origin = match saved.bsec_version {
+        Some(version) => format!("from BSEC {}", state::version_name(version)),
+        None => String::from("without header"),
+    };
+
+    // A fresh BSEC instance tells whether the state would be loaded
+    let mut bsec = Bsec::init()?;
+    let version = bsec.get_version()?;
+    let verdict = match bsec.set_state(&saved.blob) {
+        Ok(()) => format!(
+            "accepted by BSEC {}",
+            state::version_name(state::version_bytes(&version))
+        ),
+        Err(e) => format!("rejected, {}", e),
+    };
+
+    println!(
+        "{}: {} bytes {}, saved {}, {}",
+        file.display(),
+        saved.blob.len(),
+        origin,
+        saved_at.format("%Y-%m-%d %H:%M:%S"),
+        verdict
+    );
+
+    Ok(())
+}
+
+/// Copy a state file to `<file>.<date and time>`.
+fn backup_state(file: &Path) -> Result<PathBuf, Error> {
+    let mut name = file.as_os_str().to_os_string();
+    name.push(Local::now().format(".%Y%m%d-%H%M%S").to_string());
+    let backup = PathBuf::from(name);
+
+    fs::copy(file, &backup)?;
+
+    Ok(backup)
+}
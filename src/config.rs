@@ -0,0 +1,1765 @@
+use crate::bsec::{self, bsec_sensor_configuration_t, BSEC_SAMPLE_RATE_LP, BSEC_SAMPLE_RATE_SCAN};
+use crate::influx;
+use crate::sink::SinkOptions;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fmt, fs, io};
+use toml::{Table, Value};
+
+/// A configuration value that is missing or could not be parsed.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(key: impl Into<String>, message: impl Into<String>) -> ConfigError {
+        ConfigError {
+            key: key.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid config for {}: {}", self.key, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigError> for io::Error {
+    fn from(err: ConfigError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// Keys of the config file and the environment variables they stand for.
+/// The queue settings of the outputs are added by `env_key`.
+const SETTINGS: &[(&str, &str)] = &[
+    ("sensors", "SENSORS"),
+    ("sample_rate", "SAMPLE_RATE"),
+    ("state_file", "STATE_FILE"),
+    ("state_save_interval", "STATE_SAVE_INTERVAL"),
+    ("state_save_min_accuracy", "STATE_SAVE_MIN_ACCURACY"),
+    ("state_backups", "STATE_BACKUPS"),
+    ("sensor_reset_after", "SENSOR_RESET_AFTER"),
+    ("sensor_give_up_after", "SENSOR_GIVE_UP_AFTER"),
+    ("temperature_offset", "TEMPERATURE_OFFSET"),
+    ("iaq_min_accuracy", "IAQ_MIN_ACCURACY"),
+    ("gas_labels", "GAS_LABELS"),
+    ("mock", "BME_MOCK"),
+    ("record_file", "RECORD_FILE"),
+    ("replay_file", "REPLAY_FILE"),
+    ("control_socket", "CONTROL_SOCKET"),
+    ("log_target", "LOG_TARGET"),
+    ("bsec.config_file", "BSEC_CONFIG_FILE"),
+    ("bsec.outputs", "BSEC_OUTPUTS"),
+    ("graphite.url", "GRAPHITE_URL"),
+    ("graphite.protocol", "GRAPHITE_PROTOCOL"),
+    ("graphite.prefix", "GRAPHITE_PREFIX"),
+    ("graphite.path_template", "GRAPHITE_PATH_TEMPLATE"),
+    ("graphite.tags", "GRAPHITE_TAGS"),
+    ("graphite.batch_size", "GRAPHITE_BATCH_SIZE"),
+    ("graphite.max_backoff", "GRAPHITE_MAX_BACKOFF"),
+    ("graphite.tls.enabled", "GRAPHITE_TLS"),
+    ("graphite.tls.ca_file", "GRAPHITE_TLS_CA_FILE"),
+    ("graphite.tls.cert_file", "GRAPHITE_TLS_CERT_FILE"),
+    ("graphite.tls.key_file", "GRAPHITE_TLS_KEY_FILE"),
+    ("graphite.tls.server_name", "GRAPHITE_TLS_SERVER_NAME"),
+    ("graphite.spool.dir", "GRAPHITE_SPOOL_DIR"),
+    ("graphite.spool.max_mb", "GRAPHITE_SPOOL_MAX_MB"),
+    ("graphite.spool.max_age", "GRAPHITE_SPOOL_MAX_AGE"),
+    ("prometheus.listen", "PROMETHEUS_LISTEN"),
+    ("mqtt.url", "MQTT_URL"),
+    ("mqtt.username", "MQTT_USERNAME"),
+    ("mqtt.password", "MQTT_PASSWORD"),
+    ("mqtt.client_id", "MQTT_CLIENT_ID"),
+    ("mqtt.topic_prefix", "MQTT_TOPIC_PREFIX"),
+    ("mqtt.discovery_prefix", "MQTT_DISCOVERY_PREFIX"),
+    ("influx.url", "INFLUX_URL"),
+    ("influx.token", "INFLUX_TOKEN"),
+    ("influx.batch_size", "INFLUX_BATCH_SIZE"),
+    ("influx.flush_interval", "INFLUX_FLUSH_INTERVAL"),
+    ("influx.gzip", "INFLUX_GZIP"),
+    ("file.path", "OUTPUT_FILE"),
+];
+
+/// Queue settings every output has, e.g. `graphite.retries` for
+/// `GRAPHITE_RETRIES`, with the defaults for all of them under `sink`.
+const SINK_SETTINGS: [&str; 3] = ["queue_size", "retries", "retry_delay"];
+
+/// The environment variable a key of the config file stands for.
+fn env_key(key: &str) -> Option<String> {
+    if let Some((_, env_key)) = SETTINGS.iter().find(|(known, _)| *known == key) {
+        return Some(String::from(*env_key));
+    }
+
+    let (section, name) = key.split_once('.')?;
+    if (section == "sink" || SINK_NAMES.contains(&section)) && SINK_SETTINGS.contains(&name) {
+        return Some(key.replace('.', "_").to_uppercase());
+    }
+
+    None
+}
+
+/// Settings looked up by their environment variable names, in the
+/// environment first and then in the config file.
+#[derive(Default)]
+pub struct Vars {
+    path: Option<PathBuf>,
+    /// Values from the file by environment variable name, with their key in
+    /// the file.
+    file: BTreeMap<String, (String, String)>,
+    /// Sensors given as `[[sensors]]` tables in the file, which are read as
+    /// they are rather than as text.
+    sensors: Option<Vec<SensorConfig>>,
+}
+
+impl Vars {
+    /// Read the config file at `path`. Unknown keys are rejected, so typos do
+    /// not go unnoticed.
+    pub fn load(path: &Path) -> Result<Vars, ConfigError> {
+        let file_error = |message: String| ConfigError::new(path.display().to_string(), message);
+
+        let text = fs::read_to_string(path).map_err(|e| file_error(e.to_string()))?;
+        let table = text
+            .parse::<Table>()
+            .map_err(|e| file_error(e.to_string().trim_end().to_string()))?;
+
+        let mut vars = Vars {
+            path: Some(path.to_path_buf()),
+            file: BTreeMap::new(),
+            sensors: None,
+        };
+        vars.read_table("", &table)?;
+
+        Ok(vars)
+    }
+
+    fn read_table(&mut self, prefix: &str, table: &Table) -> Result<(), ConfigError> {
+        for (name, value) in table {
+            let key = if prefix.is_empty() {
+                name.clone()
+            } else {
+                format!("{}.{}", prefix, name)
+            };
+
+            match (env_key(&key), value) {
+                (Some(_), Value::Array(items))
+                    if key == "sensors" && items.iter().any(Value::is_table) =>
+                {
+                    let sensors = sensors_from_tables(items)
+                        .map_err(|message| self.file_error(&key, message))?;
+                    self.sensors = Some(sensors);
+                }
+                (Some(env_key), value) => {
+                    let value = match key.as_str() {
+                        "graphite.tags" => tags_value(value),
+                        _ => setting_value(value),
+                    }
+                    .map_err(|message| self.file_error(&key, message))?;
+                    self.file.insert(env_key, (key, value));
+                }
+                (None, Value::Table(table)) => self.read_table(&key, table)?,
+                (None, _) => return Err(self.file_error(&key, String::from("unknown setting"))),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn file_error(&self, key: &str, message: String) -> ConfigError {
+        match self.path.as_ref() {
+            Some(path) => ConfigError::new(format!("{} in {}", key, path.display()), message),
+            None => ConfigError::new(key, message),
+        }
+    }
+
+    /// The value of the environment variable `key`, or of the setting in the
+    /// config file standing for it.
+    pub fn var(&self, key: &str) -> Result<String, env::VarError> {
+        match env_var(key) {
+            Err(env::VarError::NotPresent) => self
+                .file
+                .get(key)
+                .filter(|(_, value)| !value.trim().is_empty())
+                .map(|(_, value)| value.clone())
+                .ok_or(env::VarError::NotPresent),
+            result => result,
+        }
+    }
+
+    /// The sensors in `SENSORS`, or else in the config file.
+    fn sensors(&self) -> Result<Vec<SensorConfig>, ConfigError> {
+        if let (Err(env::VarError::NotPresent), Some(sensors)) =
+            (env_var("SENSORS"), self.sensors.as_ref())
+        {
+            return Ok(sensors.clone());
+        }
+
+        match self.var("SENSORS") {
+            Ok(spec) => {
+                parse_sensors(&spec).map_err(|message| ConfigError::new("SENSORS", message))
+            }
+            Err(_) => Ok(Vec::new()),
+        }
+    }
+
+    /// Point an error about a value from the config file at its key there.
+    fn locate(&self, err: ConfigError) -> ConfigError {
+        match self.file.get(&err.key) {
+            Some((key, _)) if env_var(&err.key).is_err() => self.file_error(key, err.message),
+            _ => err,
+        }
+    }
+}
+
+/// The environment variable `key`, treating an empty value such as
+/// `GRAPHITE_URL=` as unset.
+fn env_var(key: &str) -> Result<String, env::VarError> {
+    match env::var(key) {
+        Ok(value) if value.trim().is_empty() => Err(env::VarError::NotPresent),
+        result => result,
+    }
+}
+
+/// A value of the config file as the text its environment variable would
+/// hold, with lists joined by commas.
+fn setting_value(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(text) => Ok(text.clone()),
+        Value::Integer(number) => Ok(number.to_string()),
+        Value::Float(number) => Ok(number.to_string()),
+        Value::Boolean(flag) => Ok(flag.to_string()),
+        Value::Array(items) => {
+            let mut texts = Vec::with_capacity(items.len());
+            for item in items {
+                let text = match item {
+                    Value::Array(_) | Value::Table(_) => {
+                        return Err(String::from("expected a list of strings or numbers"))
+                    }
+                    item => setting_value(item)?,
+                };
+                if text.contains(',') {
+                    return Err(format!("'{}' cannot contain ','", text));
+                }
+                texts.push(text);
+            }
+            Ok(texts.join(","))
+        }
+        Value::Datetime(_) | Value::Table(_) => {
+            Err(String::from("expected a string, number, boolean or list"))
+        }
+    }
+}
+
+/// Tags given as a table, e.g. `{ room = "{location}" }`, as the list
+/// `parse_tags` reads.
+fn tags_value(value: &Value) -> Result<String, String> {
+    let table = match value {
+        Value::Table(table) => table,
+        value => return setting_value(value),
+    };
+
+    let mut tags = Vec::with_capacity(table.len());
+    for (name, value) in table {
+        let value = match value {
+            Value::String(text) => text,
+            _ => return Err(format!("value of tag '{}' is not a string", name)),
+        };
+        if name.contains(',') || value.contains(',') {
+            return Err(format!("tag '{}' cannot contain ','", name));
+        }
+        tags.push(format!("{}={}", name, value));
+    }
+
+    Ok(tags.join(","))
+}
+
+/// `[[sensors]]` tables with `bus`, `address` and optionally `name` and
+/// `location`.
+fn sensors_from_tables(items: &[Value]) -> Result<Vec<SensorConfig>, String> {
+    let mut sensors = Vec::with_capacity(items.len());
+
+    for (i, sensor) in items.iter().enumerate() {
+        let table = sensor
+            .as_table()
+            .ok_or_else(|| format!("sensor {} is not a table with bus and address", i + 1))?;
+
+        if let Some(key) = table
+            .keys()
+            .find(|key| !["bus", "address", "name", "location"].contains(&key.as_str()))
+        {
+            return Err(format!(
+                "unknown key '{}' in sensor {}, expected bus, address, name or location",
+                key,
+                i + 1
+            ));
+        }
+
+        let field = |name: &str| -> Result<Option<String>, String> {
+            match table.get(name) {
+                Some(Value::String(text)) => Ok(Some(text.clone())),
+                Some(Value::Integer(number)) => Ok(Some(number.to_string())),
+                Some(_) => Err(format!(
+                    "{} of sensor {} is not a string or number",
+                    name,
+                    i + 1
+                )),
+                None => Ok(None),
+            }
+        };
+
+        let bus = field("bus")?.ok_or_else(|| format!("sensor {} has no bus", i + 1))?;
+        let address =
+            field("address")?.ok_or_else(|| format!("sensor {} has no address", i + 1))?;
+        let sensor = sensor_config(
+            &bus,
+            &address,
+            field("name")?.as_deref(),
+            field("location")?.as_deref(),
+        )?;
+
+        add_sensor(&mut sensors, sensor, &format!("sensor {}", i + 1))?;
+    }
+
+    Ok(sensors)
+}
+
+fn path_value(path: &Path) -> Value {
+    Value::String(path.to_string_lossy().into_owned())
+}
+
+fn strings_value(texts: &[String]) -> Value {
+    Value::Array(texts.iter().cloned().map(Value::String).collect())
+}
+
+fn integer_or_string(text: String) -> Value {
+    match text.parse::<i64>() {
+        Ok(number) => Value::Integer(number),
+        Err(_) => Value::String(text),
+    }
+}
+
+/// A sensor given in `SENSORS` as `<bus>:<address>[:<name>[:<location>]]`,
+/// or as a `[[sensors]]` table in the config file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SensorConfig {
+    pub path: PathBuf,
+    pub address: u8,
+    pub name: Option<String>,
+    pub location: Option<String>,
+}
+
+/// How lines are sent to Graphite, set with `GRAPHITE_PROTOCOL`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphiteProtocol {
+    /// Plaintext lines over TCP, usually on port 2003.
+    Plaintext,
+    /// Length prefixed pickled batches over TCP, usually on port 2004.
+    Pickle,
+    /// Plaintext lines in UDP datagrams.
+    Udp,
+}
+
+/// Graphite server to send readings to, see `graphite::Client`.
+#[derive(Clone)]
+pub struct GraphiteConfig {
+    /// `host:port` of the listener for `protocol`.
+    pub url: String,
+    pub protocol: GraphiteProtocol,
+    /// Replaces `{prefix}` in the path template and tags.
+    pub prefix: String,
+    /// Metric path with placeholders, see `PATH_PLACEHOLDERS`.
+    pub path_template: String,
+    /// Graphite 1.1 tags as names and value templates.
+    pub tags: Vec<(String, String)>,
+    pub tls: Option<TlsConfig>,
+    /// Lines sent at once.
+    pub batch_size: usize,
+    /// Longest wait between reconnects.
+    pub max_backoff: Duration,
+    pub spool: Option<SpoolConfig>,
+}
+
+/// TLS for the Graphite connection, see `graphite::State`.
+#[derive(Clone)]
+pub struct TlsConfig {
+    /// PEM bundle of the CAs to trust.
+    pub ca_file: PathBuf,
+    /// PEM client certificate chain and key for mutual TLS.
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    /// Name sent as SNI and checked against the server certificate.
+    pub server_name: String,
+}
+
+/// Where unsent Graphite lines are kept, see `spool::Spool`.
+#[derive(Clone)]
+pub struct SpoolConfig {
+    pub dir: PathBuf,
+    /// Oldest points are dropped once the spool is bigger than this.
+    pub max_bytes: u64,
+    /// Points older than this are dropped instead of sent.
+    pub max_age: Duration,
+}
+
+/// When to save the BSEC state while running, besides on exit, see
+/// `state::write`.
+#[derive(Clone, Copy, Debug)]
+pub struct StateSaveConfig {
+    /// `None` to only save on exit.
+    pub interval: Option<Duration>,
+    /// The state is not saved before the IAQ accuracy reaches this, so an
+    /// uncalibrated state does not replace a calibrated one.
+    pub min_accuracy: u8,
+    /// Number of earlier state files kept to fall back on.
+    pub backups: usize,
+}
+
+/// How to recover from a sensor that cannot be read, see
+/// `Sensor::measure`.
+#[derive(Clone, Copy, Debug)]
+pub struct RecoveryConfig {
+    /// Failed measurements in a row after which the sensor is reset and
+    /// initialized again.
+    pub reset_after: u32,
+    /// A sensor is given up on once it has failed for this long, and the
+    /// program stops when none is left, `None` to keep trying.
+    pub give_up_after: Option<Duration>,
+}
+
+/// MQTT broker to publish readings to, see `mqtt::Publisher`.
+#[derive(Clone)]
+pub struct MqttConfig {
+    /// `host:port` of the broker.
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub client_id: String,
+    /// State and availability topics are published below this prefix.
+    pub topic_prefix: String,
+    /// Prefix Home Assistant listens for discovery configs on.
+    pub discovery_prefix: String,
+}
+
+/// InfluxDB to write readings to, see `influx::Writer`.
+#[derive(Clone)]
+pub struct InfluxConfig {
+    /// `udp://host:port`, or the HTTP write endpoint including its query,
+    /// e.g. `http://host:8086/api/v2/write?org=home&bucket=bme`.
+    pub url: String,
+    pub token: Option<String>,
+    /// Points sent in one HTTP request.
+    pub batch_size: usize,
+    /// Longest time a point waits for its batch to fill up.
+    pub flush_interval: Duration,
+    pub gzip: bool,
+}
+
+/// Outputs that can be enabled, used for their `<NAME>_QUEUE_SIZE`,
+/// `<NAME>_RETRIES` and `<NAME>_RETRY_DELAY` settings.
+pub const SINK_NAMES: [&str; 5] = ["graphite", "prometheus", "mqtt", "influx", "file"];
+
+pub struct Config {
+    pub graphite: Option<GraphiteConfig>,
+    /// Address to serve Prometheus metrics on, e.g. `0.0.0.0:9100`.
+    pub prometheus_listen: Option<String>,
+    pub mqtt: Option<MqttConfig>,
+    pub influx: Option<InfluxConfig>,
+    /// File to append every reading to as JSON lines.
+    pub output_file: Option<PathBuf>,
+    /// Queue size and retries of each output, by name.
+    pub sink_options: BTreeMap<&'static str, SinkOptions>,
+    pub mock: bool,
+    pub record_file: Option<PathBuf>,
+    pub replay_file: Option<PathBuf>,
+    pub bsec_config_file: Option<PathBuf>,
+    pub sample_rate: f32,
+    pub outputs: Vec<bsec_sensor_configuration_t>,
+    /// IAQ and VOC values with a lower accuracy are not sent.
+    pub min_accuracy: u8,
+    /// Class names for `gas_estimate_1` to `gas_estimate_4` in scan mode.
+    pub gas_labels: Vec<String>,
+    /// Sensors to use, every BME68x found on `/dev/i2c-*` when empty.
+    pub sensors: Vec<SensorConfig>,
+    /// BSEC state of a single unnamed sensor, the state files of the others
+    /// get their sensor name added, see `sensor::file_for_sensor`.
+    pub state_file: PathBuf,
+    pub state_save: StateSaveConfig,
+    pub recovery: RecoveryConfig,
+    /// Replaces the heat source offset BSEC subtracts from the temperature,
+    /// which otherwise depends on the sample rate.
+    pub temperature_offset: Option<f32>,
+    /// Unix socket to accept commands such as `reload` on, see `control`.
+    pub control_socket: Option<PathBuf>,
+    /// Log to journald with structured fields instead of to stderr.
+    pub log_to_journal: bool,
+}
+
+impl Config {
+    pub fn sink_options(&self, name: &str) -> SinkOptions {
+        self.sink_options.get(name).copied().unwrap_or_default()
+    }
+
+    /// Read the config file at `path`, if any, with the environment
+    /// variables taking precedence over it.
+    pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+        let vars = match path {
+            Some(path) => Vars::load(path)?,
+            None => Vars::default(),
+        };
+
+        Config::from_vars(&vars).map_err(|err| vars.locate(err))
+    }
+
+    /// Readings have to go somewhere when running, the other commands need
+    /// no outputs.
+    pub fn check_outputs(&self) -> Result<(), ConfigError> {
+        if self.graphite.is_none()
+            && self.prometheus_listen.is_none()
+            && self.mqtt.is_none()
+            && self.influx.is_none()
+            && self.output_file.is_none()
+        {
+            return Err(ConfigError::new(
+                "outputs",
+                "none enabled, set GRAPHITE_URL, PROMETHEUS_LISTEN, MQTT_URL, INFLUX_URL or \
+                 OUTPUT_FILE, or their keys in the config file",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The effective configuration in the format of the config file, with
+    /// the defaults filled in. Passwords and tokens are left out.
+    pub fn to_toml(&self) -> String {
+        let mut root = Table::new();
+
+        if !self.sensors.is_empty() {
+            let sensors = self
+                .sensors
+                .iter()
+                .map(|sensor| {
+                    let mut table = Table::new();
+                    let bus = sensor.path.to_string_lossy().replace("/dev/i2c-", "");
+                    table.insert(String::from("bus"), integer_or_string(bus));
+                    table.insert(
+                        String::from("address"),
+                        Value::String(format!("{:#04x}", sensor.address)),
+                    );
+                    if let Some(name) = sensor.name.as_ref() {
+                        table.insert(String::from("name"), Value::String(name.clone()));
+                    }
+                    if let Some(location) = sensor.location.as_ref() {
+                        table.insert(String::from("location"), Value::String(location.clone()));
+                    }
+                    Value::Table(table)
+                })
+                .collect();
+            root.insert(String::from("sensors"), Value::Array(sensors));
+        }
+
+        root.insert(
+            String::from("sample_rate"),
+            Value::String(String::from(bsec::mode_name(self.sample_rate))),
+        );
+        root.insert(String::from("state_file"), path_value(&self.state_file));
+        root.insert(
+            String::from("state_save_interval"),
+            Value::Integer(
+                self.state_save
+                    .interval
+                    .map(|interval| interval.as_secs() as i64)
+                    .unwrap_or(0),
+            ),
+        );
+        root.insert(
+            String::from("state_save_min_accuracy"),
+            Value::Integer(self.state_save.min_accuracy.into()),
+        );
+        root.insert(
+            String::from("state_backups"),
+            Value::Integer(self.state_save.backups as i64),
+        );
+        root.insert(
+            String::from("sensor_reset_after"),
+            Value::Integer(self.recovery.reset_after.into()),
+        );
+        root.insert(
+            String::from("sensor_give_up_after"),
+            Value::Integer(
+                self.recovery
+                    .give_up_after
+                    .map(|timeout| timeout.as_secs() as i64)
+                    .unwrap_or(0),
+            ),
+        );
+        if let Some(offset) = self.temperature_offset {
+            root.insert(
+                String::from("temperature_offset"),
+                Value::Float(offset.into()),
+            );
+        }
+        root.insert(
+            String::from("iaq_min_accuracy"),
+            Value::Integer(self.min_accuracy.into()),
+        );
+        root.insert(String::from("gas_labels"), strings_value(&self.gas_labels));
+        root.insert(String::from("mock"), Value::Boolean(self.mock));
+        if let Some(record_file) = self.record_file.as_ref() {
+            root.insert(String::from("record_file"), path_value(record_file));
+        }
+        if let Some(replay_file) = self.replay_file.as_ref() {
+            root.insert(String::from("replay_file"), path_value(replay_file));
+        }
+        if let Some(control_socket) = self.control_socket.as_ref() {
+            root.insert(String::from("control_socket"), path_value(control_socket));
+        }
+        root.insert(
+            String::from("log_target"),
+            Value::String(String::from(if self.log_to_journal {
+                "journald"
+            } else {
+                "stderr"
+            })),
+        );
+
+        let mut bsec = Table::new();
+        if let Some(config_file) = self.bsec_config_file.as_ref() {
+            bsec.insert(String::from("config_file"), path_value(config_file));
+        }
+        let outputs: Vec<String> = self
+            .outputs
+            .iter()
+            .map(|output| {
+                let name = bsec::virtual_sensor_name(output.sensor_id);
+                if output.sample_rate == self.sample_rate {
+                    String::from(name)
+                } else {
+                    format!("{}:{}", name, bsec::sample_rate_name(output.sample_rate))
+                }
+            })
+            .collect();
+        bsec.insert(String::from("outputs"), strings_value(&outputs));
+        root.insert(String::from("bsec"), Value::Table(bsec));
+
+        if let Some(graphite) = self.graphite.as_ref() {
+            let mut table = self.sink_table("graphite");
+            table.insert(String::from("url"), Value::String(graphite.url.clone()));
+            let protocol = match graphite.protocol {
+                GraphiteProtocol::Plaintext => "plaintext",
+                GraphiteProtocol::Pickle => "pickle",
+                GraphiteProtocol::Udp => "udp",
+            };
+            table.insert(
+                String::from("protocol"),
+                Value::String(String::from(protocol)),
+            );
+            table.insert(
+                String::from("prefix"),
+                Value::String(graphite.prefix.clone()),
+            );
+            table.insert(
+                String::from("path_template"),
+                Value::String(graphite.path_template.clone()),
+            );
+            let tags = graphite
+                .tags
+                .iter()
+                .map(|(name, value)| (name.clone(), Value::String(value.clone())))
+                .collect();
+            table.insert(String::from("tags"), Value::Table(tags));
+            table.insert(
+                String::from("batch_size"),
+                Value::Integer(graphite.batch_size as i64),
+            );
+            table.insert(
+                String::from("max_backoff"),
+                Value::Integer(graphite.max_backoff.as_secs() as i64),
+            );
+            if let Some(tls) = graphite.tls.as_ref() {
+                let mut tls_table = Table::new();
+                tls_table.insert(String::from("enabled"), Value::Boolean(true));
+                tls_table.insert(String::from("ca_file"), path_value(&tls.ca_file));
+                if let Some(cert_file) = tls.cert_file.as_ref() {
+                    tls_table.insert(String::from("cert_file"), path_value(cert_file));
+                }
+                if let Some(key_file) = tls.key_file.as_ref() {
+                    tls_table.insert(String::from("key_file"), path_value(key_file));
+                }
+                tls_table.insert(
+                    String::from("server_name"),
+                    Value::String(tls.server_name.clone()),
+                );
+                table.insert(String::from("tls"), Value::Table(tls_table));
+            }
+            if let Some(spool) = graphite.spool.as_ref() {
+                let mut spool_table = Table::new();
+                spool_table.insert(String::from("dir"), path_value(&spool.dir));
+                spool_table.insert(
+                    String::from("max_mb"),
+                    Value::Integer((spool.max_bytes / 1024 / 1024) as i64),
+                );
+                spool_table.insert(
+                    String::from("max_age"),
+                    Value::Integer(spool.max_age.as_secs() as i64),
+                );
+                table.insert(String::from("spool"), Value::Table(spool_table));
+            }
+            root.insert(String::from("graphite"), Value::Table(table));
+        }
+
+        if let Some(listen) = self.prometheus_listen.as_ref() {
+            let mut table = self.sink_table("prometheus");
+            table.insert(String::from("listen"), Value::String(listen.clone()));
+            root.insert(String::from("prometheus"), Value::Table(table));
+        }
+
+        if let Some(mqtt) = self.mqtt.as_ref() {
+            let mut table = self.sink_table("mqtt");
+            table.insert(String::from("url"), Value::String(mqtt.url.clone()));
+            if let Some(username) = mqtt.username.as_ref() {
+                table.insert(String::from("username"), Value::String(username.clone()));
+            }
+            table.insert(
+                String::from("client_id"),
+                Value::String(mqtt.client_id.clone()),
+            );
+            table.insert(
+                String::from("topic_prefix"),
+                Value::String(mqtt.topic_prefix.clone()),
+            );
+            table.insert(
+                String::from("discovery_prefix"),
+                Value::String(mqtt.discovery_prefix.clone()),
+            );
+            root.insert(String::from("mqtt"), Value::Table(table));
+        }
+
+        if let Some(influx) = self.influx.as_ref() {
+            let mut table = self.sink_table("influx");
+            table.insert(String::from("url"), Value::String(influx.url.clone()));
+            table.insert(
+                String::from("batch_size"),
+                Value::Integer(influx.batch_size as i64),
+            );
+            table.insert(
+                String::from("flush_interval"),
+                Value::Integer(influx.flush_interval.as_secs() as i64),
+            );
+            table.insert(String::from("gzip"), Value::Boolean(influx.gzip));
+            root.insert(String::from("influx"), Value::Table(table));
+        }
+
+        if let Some(output_file) = self.output_file.as_ref() {
+            let mut table = self.sink_table("file");
+            table.insert(String::from("path"), path_value(output_file));
+            root.insert(String::from("file"), Value::Table(table));
+        }
+
+        toml::to_string(&root).unwrap_or_default()
+    }
+
+    /// The queue settings of the output `name`.
+    fn sink_table(&self, name: &str) -> Table {
+        let options = self.sink_options(name);
+        let mut table = Table::new();
+        table.insert(
+            String::from("queue_size"),
+            Value::Integer(options.queue_size as i64),
+        );
+        table.insert(
+            String::from("retries"),
+            Value::Integer(options.retries.into()),
+        );
+        table.insert(
+            String::from("retry_delay"),
+            Value::Float(options.retry_delay.as_secs_f64()),
+        );
+        table
+    }
+
+    fn from_vars(vars: &Vars) -> Result<Config, ConfigError> {
+        let graphite = match vars.var("GRAPHITE_URL") {
+            Ok(url) => Some(graphite_from_vars(vars, url)?),
+            Err(_) => None,
+        };
+        let prometheus_listen = vars.var("PROMETHEUS_LISTEN").ok();
+
+        let mqtt = vars.var("MQTT_URL").ok().map(|url| MqttConfig {
+            url,
+            username: vars.var("MQTT_USERNAME").ok(),
+            password: vars.var("MQTT_PASSWORD").ok(),
+            client_id: vars
+                .var("MQTT_CLIENT_ID")
+                .unwrap_or_else(|_| String::from("bme-sensors")),
+            topic_prefix: vars
+                .var("MQTT_TOPIC_PREFIX")
+                .unwrap_or_else(|_| String::from("bme-sensors")),
+            discovery_prefix: vars
+                .var("MQTT_DISCOVERY_PREFIX")
+                .unwrap_or_else(|_| String::from("homeassistant")),
+        });
+
+        let influx = match vars.var("INFLUX_URL") {
+            Ok(url) => Some(influx_from_vars(vars, url)?),
+            Err(_) => None,
+        };
+
+        let output_file = vars.var("OUTPUT_FILE").ok().map(PathBuf::from);
+
+        if let Some(mqtt) = mqtt.as_ref() {
+            if mqtt.password.is_some() && mqtt.username.is_none() {
+                return Err(ConfigError::new(
+                    "MQTT_PASSWORD",
+                    "needs MQTT_USERNAME to be set",
+                ));
+            }
+            if mqtt.topic_prefix.is_empty() || mqtt.topic_prefix.contains(|c| c == '+' || c == '#')
+            {
+                return Err(ConfigError::new(
+                    "MQTT_TOPIC_PREFIX",
+                    format!("'{}' is not a valid topic", mqtt.topic_prefix),
+                ));
+            }
+        }
+
+        // The program runs in one of the BSEC modes, not at any rate
+        let sample_rate = match vars.var("SAMPLE_RATE") {
+            Ok(mode) => match bsec::parse_sample_rate(mode.trim()) {
+                Ok(rate) if bsec::mode_name(rate) != "custom" => rate,
+                _ => {
+                    return Err(ConfigError::new(
+                        "SAMPLE_RATE",
+                        format!(
+                            "unknown mode '{}', expected one of ulp, lp, cont, scan",
+                            mode.trim()
+                        ),
+                    ))
+                }
+            },
+            Err(_) => BSEC_SAMPLE_RATE_LP as f32,
+        };
+
+        let outputs = match vars.var("BSEC_OUTPUTS") {
+            Ok(spec) => bsec::parse_outputs(&spec, sample_rate)
+                .map_err(|message| ConfigError::new("BSEC_OUTPUTS", message))?,
+            Err(_) => bsec::default_outputs(sample_rate),
+        };
+
+        let min_accuracy = match vars.var("IAQ_MIN_ACCURACY") {
+            Ok(value) => match value.trim().parse::<u8>() {
+                Ok(accuracy) if accuracy <= 3 => accuracy,
+                _ => {
+                    return Err(ConfigError::new(
+                        "IAQ_MIN_ACCURACY",
+                        format!("'{}' is not an accuracy between 0 and 3", value),
+                    ))
+                }
+            },
+            Err(_) => 0,
+        };
+
+        let bsec_config_file = vars.var("BSEC_CONFIG_FILE").ok().map(PathBuf::from);
+
+        if sample_rate == BSEC_SAMPLE_RATE_SCAN as f32 && bsec_config_file.is_none() {
+            return Err(ConfigError::new(
+                "BSEC_CONFIG_FILE",
+                "scan mode needs a config exported from BME AI Studio",
+            ));
+        }
+
+        let mut gas_labels: Vec<String> = (1..=4).map(|i| format!("gas_estimate_{}", i)).collect();
+
+        if let Ok(labels) = vars.var("GAS_LABELS") {
+            let labels: Vec<&str> = labels.split(',').map(|label| label.trim()).collect();
+            if labels.len() > 4 {
+                return Err(ConfigError::new(
+                    "GAS_LABELS",
+                    format!("BSEC has 4 gas estimates, found {} labels", labels.len()),
+                ));
+            }
+            for (i, label) in labels.into_iter().enumerate() {
+                if label.is_empty() || label.contains(|c: char| c.is_whitespace() || c == '.') {
+                    return Err(ConfigError::new(
+                        "GAS_LABELS",
+                        format!("'{}' is not a valid metric name", label),
+                    ));
+                }
+                // Gas estimates are InfluxDB fields named after their label
+                if influx::FIELD_KEYS.contains(&label) || label.ends_with("_accuracy") {
+                    return Err(ConfigError::new(
+                        "GAS_LABELS",
+                        format!("'{}' is already the name of an output", label),
+                    ));
+                }
+                if gas_labels[..i].iter().any(|known| known == label) {
+                    return Err(ConfigError::new(
+                        "GAS_LABELS",
+                        format!("'{}' is listed twice", label),
+                    ));
+                }
+                gas_labels[i] = String::from(label);
+            }
+        }
+
+        let sensors = vars.sensors()?;
+
+        let state_file = vars
+            .var("STATE_FILE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("last_state.bin"));
+        if state_file.file_name().is_none() {
+            return Err(ConfigError::new(
+                "STATE_FILE",
+                format!("'{}' is not a file name", state_file.display()),
+            ));
+        }
+
+        let state_save = state_save_from_vars(vars)?;
+        let recovery = recovery_from_vars(vars)?;
+
+        let temperature_offset = match vars.var("TEMPERATURE_OFFSET") {
+            Ok(value) => match value.trim().parse::<f32>() {
+                Ok(offset) if (-20.0..=20.0).contains(&offset) => Some(offset),
+                _ => {
+                    return Err(ConfigError::new(
+                        "TEMPERATURE_OFFSET",
+                        format!("'{}' is not a number of degrees between -20 and 20", value),
+                    ))
+                }
+            },
+            Err(_) => None,
+        };
+
+        let mock = match vars.var("BME_MOCK") {
+            Ok(value) => match value.trim() {
+                "1" | "true" => true,
+                "0" | "false" => false,
+                _ => {
+                    return Err(ConfigError::new(
+                        "BME_MOCK",
+                        format!("'{}' is not true or false", value),
+                    ))
+                }
+            },
+            Err(_) => false,
+        };
+
+        let log_to_journal = match vars.var("LOG_TARGET") {
+            Ok(value) => match value.trim() {
+                "stderr" => false,
+                "journald" => true,
+                _ => {
+                    return Err(ConfigError::new(
+                        "LOG_TARGET",
+                        format!("'{}' is not stderr or journald", value),
+                    ))
+                }
+            },
+            Err(_) => false,
+        };
+
+        let defaults = sink_options_from_vars(vars, "SINK", SinkOptions::default())?;
+        let mut sink_options = BTreeMap::new();
+        for name in SINK_NAMES {
+            let options = sink_options_from_vars(vars, &name.to_uppercase(), defaults)?;
+            sink_options.insert(name, options);
+        }
+
+        Ok(Config {
+            graphite,
+            prometheus_listen,
+            mqtt,
+            influx,
+            output_file,
+            sink_options,
+            mock,
+            record_file: vars.var("RECORD_FILE").ok().map(PathBuf::from),
+            replay_file: vars.var("REPLAY_FILE").ok().map(PathBuf::from),
+            bsec_config_file,
+            sample_rate,
+            outputs,
+            min_accuracy,
+            gas_labels,
+            sensors,
+            state_file,
+            state_save,
+            recovery,
+            temperature_offset,
+            control_socket: vars.var("CONTROL_SOCKET").ok().map(PathBuf::from),
+            log_to_journal,
+        })
+    }
+}
+
+/// Read `<PREFIX>_QUEUE_SIZE`, `<PREFIX>_RETRIES` and `<PREFIX>_RETRY_DELAY`,
+/// keeping `defaults` for the unset ones.
+fn sink_options_from_vars(
+    vars: &Vars,
+    prefix: &str,
+    defaults: SinkOptions,
+) -> Result<SinkOptions, ConfigError> {
+    let mut options = defaults;
+
+    let key = format!("{}_QUEUE_SIZE", prefix);
+    if let Ok(value) = vars.var(&key) {
+        options.queue_size = match value.trim().parse::<usize>() {
+            Ok(size) if size > 0 => size,
+            _ => {
+                return Err(ConfigError::new(
+                    key,
+                    format!("'{}' is not a positive number", value),
+                ))
+            }
+        };
+    }
+
+    let key = format!("{}_RETRIES", prefix);
+    if let Ok(value) = vars.var(&key) {
+        options.retries = value
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| ConfigError::new(key, format!("'{}' is not a number", value)))?;
+    }
+
+    let key = format!("{}_RETRY_DELAY", prefix);
+    if let Ok(value) = vars.var(&key) {
+        options.retry_delay = value
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|secs| secs.is_finite() && *secs >= 0.0)
+            .map(Duration::from_secs_f64)
+            .ok_or_else(|| {
+                ConfigError::new(key, format!("'{}' is not a number of seconds", value))
+            })?;
+    }
+
+    Ok(options)
+}
+
+fn graphite_from_vars(vars: &Vars, url: String) -> Result<GraphiteConfig, ConfigError> {
+    let protocol = match vars.var("GRAPHITE_PROTOCOL") {
+        Ok(value) => match value.trim() {
+            "plaintext" | "tcp" => GraphiteProtocol::Plaintext,
+            "pickle" => GraphiteProtocol::Pickle,
+            "udp" => GraphiteProtocol::Udp,
+            _ => {
+                return Err(ConfigError::new(
+                    "GRAPHITE_PROTOCOL",
+                    format!("'{}' is not plaintext, pickle or udp", value),
+                ))
+            }
+        },
+        Err(_) => GraphiteProtocol::Plaintext,
+    };
+
+    let batch_size = match vars.var("GRAPHITE_BATCH_SIZE") {
+        Ok(value) => match value.trim().parse::<usize>() {
+            Ok(size) if size > 0 => size,
+            _ => {
+                return Err(ConfigError::new(
+                    "GRAPHITE_BATCH_SIZE",
+                    format!("'{}' is not a positive number", value),
+                ))
+            }
+        },
+        Err(_) => 500,
+    };
+
+    let max_backoff = match vars.var("GRAPHITE_MAX_BACKOFF") {
+        Ok(value) => match value.trim().parse::<u64>() {
+            Ok(secs) if secs > 0 => Duration::from_secs(secs),
+            _ => {
+                return Err(ConfigError::new(
+                    "GRAPHITE_MAX_BACKOFF",
+                    format!("'{}' is not a positive number of seconds", value),
+                ))
+            }
+        },
+        Err(_) => Duration::from_secs(60),
+    };
+
+    let prefix = vars.var("GRAPHITE_PREFIX").unwrap_or_default();
+    if prefix.contains(|c: char| c.is_whitespace() || c == ';') {
+        return Err(ConfigError::new(
+            "GRAPHITE_PREFIX",
+            format!("'{}' is not a valid metric path", prefix),
+        ));
+    }
+
+    let path_template = vars
+        .var("GRAPHITE_PATH_TEMPLATE")
+        .unwrap_or_else(|_| String::from("{sensor}.{output}"));
+    check_template(&path_template)
+        .map_err(|message| ConfigError::new("GRAPHITE_PATH_TEMPLATE", message))?;
+    if !path_template.contains("{output}") {
+        return Err(ConfigError::new(
+            "GRAPHITE_PATH_TEMPLATE",
+            "must contain {output}, or all outputs end up in one series",
+        ));
+    }
+
+    let tags = match vars.var("GRAPHITE_TAGS") {
+        Ok(spec) => {
+            parse_tags(&spec).map_err(|message| ConfigError::new("GRAPHITE_TAGS", message))?
+        }
+        Err(_) => Vec::new(),
+    };
+
+    let tls = tls_from_vars(vars, &url)?;
+
+    if protocol == GraphiteProtocol::Udp && tls.is_some() {
+        return Err(ConfigError::new(
+            "GRAPHITE_TLS",
+            "cannot be used with GRAPHITE_PROTOCOL=udp",
+        ));
+    }
+
+    let spool = spool_from_vars(vars)?;
+
+    if protocol == GraphiteProtocol::Udp && spool.is_some() {
+        return Err(ConfigError::new(
+            "GRAPHITE_SPOOL_DIR",
+            "cannot be used with GRAPHITE_PROTOCOL=udp, which never notices lost points",
+        ));
+    }
+
+    Ok(GraphiteConfig {
+        url,
+        protocol,
+        prefix,
+        path_template,
+        tags,
+        tls,
+        batch_size,
+        max_backoff,
+        spool,
+    })
+}
+
+/// Placeholders of `GRAPHITE_PATH_TEMPLATE` and `GRAPHITE_TAGS`.
+pub const PATH_PLACEHOLDERS: [&str; 7] = [
+    "prefix", "host", "sensor", "bus", "address", "location", "output",
+];
+
+/// Check that every `{...}` in a template is a known placeholder.
+fn check_template(template: &str) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed '{{' in '{}'", template))?;
+        let placeholder = &rest[start + 1..start + end];
+        if !PATH_PLACEHOLDERS.contains(&placeholder) {
+            return Err(format!(
+                "unknown placeholder '{{{}}}', expected one of {}",
+                placeholder,
+                PATH_PLACEHOLDERS
+                    .iter()
+                    .map(|name| format!("{{{}}}", name))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ));
+        }
+        rest = &rest[start + end + 1..];
+    }
+
+    if template.contains(|c: char| c.is_whitespace() || c == ';') {
+        return Err(format!("'{}' contains spaces or ';'", template));
+    }
+    Ok(())
+}
+
+/// Parse tags such as `room={location},sensor=bme688-{address}`.
+fn parse_tags(spec: &str) -> Result<Vec<(String, String)>, String> {
+    let mut tags: Vec<(String, String)> = Vec::new();
+
+    for entry in spec.split(',').map(|entry| entry.trim()) {
+        if entry.is_empty() {
+            continue;
+        }
+
+        let (name, value) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("'{}' is not of the form <name>=<value>", entry))?;
+
+        if name.is_empty() || name.contains(|c: char| "; !^=~{}".contains(c)) {
+            return Err(format!("'{}' is not a valid tag name", name));
+        }
+        if name == "name" {
+            return Err(String::from(
+                "the 'name' tag is set by Graphite from the path",
+            ));
+        }
+        if tags.iter().any(|(known, _)| known == name) {
+            return Err(format!("tag '{}' is listed twice", name));
+        }
+        if value.starts_with('~') {
+            return Err(format!("tag value '{}' cannot start with '~'", value));
+        }
+        check_template(value)?;
+        if value.contains("{output}") {
+            return Err(String::from("{output} can only be used in the path"));
+        }
+
+        tags.push((String::from(name), String::from(value)));
+    }
+
+    Ok(tags)
+}
+
+fn tls_from_vars(vars: &Vars, url: &str) -> Result<Option<TlsConfig>, ConfigError> {
+    match vars.var("GRAPHITE_TLS") {
+        Ok(value) => match value.trim() {
+            "1" | "true" => {}
+            "0" | "false" => return Ok(None),
+            _ => {
+                return Err(ConfigError::new(
+                    "GRAPHITE_TLS",
+                    format!("'{}' is not true or false", value),
+                ))
+            }
+        },
+        Err(_) => return Ok(None),
+    }
+
+    let ca_file = vars
+        .var("GRAPHITE_TLS_CA_FILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("/etc/ssl/certs/ca-certificates.crt"));
+
+    let cert_file = vars.var("GRAPHITE_TLS_CERT_FILE").ok().map(PathBuf::from);
+    let key_file = vars.var("GRAPHITE_TLS_KEY_FILE").ok().map(PathBuf::from);
+    match (cert_file.is_some(), key_file.is_some()) {
+        (true, false) => {
+            return Err(ConfigError::new(
+                "GRAPHITE_TLS_KEY_FILE",
+                "missing, needed with GRAPHITE_TLS_CERT_FILE",
+            ))
+        }
+        (false, true) => {
+            return Err(ConfigError::new(
+                "GRAPHITE_TLS_CERT_FILE",
+                "missing, needed with GRAPHITE_TLS_KEY_FILE",
+            ))
+        }
+        _ => {}
+    }
+
+    // The host of GRAPHITE_URL, without port and IPv6 brackets
+    let server_name = match vars.var("GRAPHITE_TLS_SERVER_NAME") {
+        Ok(name) => name,
+        Err(_) => {
+            let host = url.rsplit_once(':').map_or(url, |(host, _)| host);
+            String::from(host.trim_start_matches('[').trim_end_matches(']'))
+        }
+    };
+    if server_name.is_empty() {
+        return Err(ConfigError::new(
+            "GRAPHITE_TLS_SERVER_NAME",
+            "missing, GRAPHITE_URL has no host name",
+        ));
+    }
+
+    Ok(Some(TlsConfig {
+        ca_file,
+        cert_file,
+        key_file,
+        server_name,
+    }))
+}
+
+fn spool_from_vars(vars: &Vars) -> Result<Option<SpoolConfig>, ConfigError> {
+    let dir = match vars.var("GRAPHITE_SPOOL_DIR") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => return Ok(None),
+    };
+
+    let max_bytes = match vars.var("GRAPHITE_SPOOL_MAX_MB") {
+        Ok(value) => match value.trim().parse::<u64>() {
+            Ok(size) if size > 0 => size * 1024 * 1024,
+            _ => {
+                return Err(ConfigError::new(
+                    "GRAPHITE_SPOOL_MAX_MB",
+                    format!("'{}' is not a positive number", value),
+                ))
+            }
+        },
+        Err(_) => 64 * 1024 * 1024,
+    };
+
+    let max_age = match vars.var("GRAPHITE_SPOOL_MAX_AGE") {
+        Ok(value) => match value.trim().parse::<u64>() {
+            Ok(secs) if secs > 0 => Duration::from_secs(secs),
+            _ => {
+                return Err(ConfigError::new(
+                    "GRAPHITE_SPOOL_MAX_AGE",
+                    format!("'{}' is not a positive number of seconds", value),
+                ))
+            }
+        },
+        Err(_) => Duration::from_secs(7 * 24 * 60 * 60),
+    };
+
+    Ok(Some(SpoolConfig {
+        dir,
+        max_bytes,
+        max_age,
+    }))
+}
+
+fn state_save_from_vars(vars: &Vars) -> Result<StateSaveConfig, ConfigError> {
+    let interval = match vars.var("STATE_SAVE_INTERVAL") {
+        Ok(value) => match value.trim().parse::<u64>() {
+            Ok(0) => None,
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            _ => {
+                return Err(ConfigError::new(
+                    "STATE_SAVE_INTERVAL",
+                    format!("'{}' is not a number of seconds", value),
+                ))
+            }
+        },
+        Err(_) => Some(Duration::from_secs(4 * 60 * 60)),
+    };
+
+    let min_accuracy = match vars.var("STATE_SAVE_MIN_ACCURACY") {
+        Ok(value) => match value.trim().parse::<u8>() {
+            Ok(accuracy) if accuracy <= 3 => accuracy,
+            _ => {
+                return Err(ConfigError::new(
+                    "STATE_SAVE_MIN_ACCURACY",
+                    format!("'{}' is not an accuracy between 0 and 3", value),
+                ))
+            }
+        },
+        Err(_) => 3,
+    };
+
+    let backups = match vars.var("STATE_BACKUPS") {
+        Ok(value) => match value.trim().parse::<usize>() {
+            Ok(backups) if backups <= 100 => backups,
+            _ => {
+                return Err(ConfigError::new(
+                    "STATE_BACKUPS",
+                    format!("'{}' is not a number from 0 to 100", value),
+                ))
+            }
+        },
+        Err(_) => 2,
+    };
+
+    Ok(StateSaveConfig {
+        interval,
+        min_accuracy,
+        backups,
+    })
+}
+
+fn recovery_from_vars(vars: &Vars) -> Result<RecoveryConfig, ConfigError> {
+    let reset_after = match vars.var("SENSOR_RESET_AFTER") {
+        Ok(value) => match value.trim().parse::<u32>() {
+            Ok(count) if count > 0 => count,
+            _ => {
+                return Err(ConfigError::new(
+                    "SENSOR_RESET_AFTER",
+                    format!("'{}' is not a positive number", value),
+                ))
+            }
+        },
+        Err(_) => 3,
+    };
+
+    let give_up_after = match vars.var("SENSOR_GIVE_UP_AFTER") {
+        Ok(value) => match value.trim().parse::<u64>() {
+            Ok(0) => None,
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            _ => {
+                return Err(ConfigError::new(
+                    "SENSOR_GIVE_UP_AFTER",
+                    format!("'{}' is not a number of seconds", value),
+                ))
+            }
+        },
+        Err(_) => Some(Duration::from_secs(10 * 60)),
+    };
+
+    Ok(RecoveryConfig {
+        reset_after,
+        give_up_after,
+    })
+}
+
+fn influx_from_vars(vars: &Vars, url: String) -> Result<InfluxConfig, ConfigError> {
+    if !url.starts_with("udp://") && !url.starts_with("http://") {
+        return Err(ConfigError::new(
+            "INFLUX_URL",
+            format!("'{}' is not a udp:// or http:// URL", url),
+        ));
+    }
+
+    let batch_size = match vars.var("INFLUX_BATCH_SIZE") {
+        Ok(value) => match value.trim().parse::<usize>() {
+            Ok(size) if size > 0 => size,
+            _ => {
+                return Err(ConfigError::new(
+                    "INFLUX_BATCH_SIZE",
+                    format!("'{}' is not a positive number", value),
+                ))
+            }
+        },
+        Err(_) => 100,
+    };
+
+    let flush_interval = match vars.var("INFLUX_FLUSH_INTERVAL") {
+        Ok(value) => value
+            .trim()
+            .parse::<u64>()
+            .map(Duration::from_secs)
+            .map_err(|_| {
+                ConfigError::new(
+                    "INFLUX_FLUSH_INTERVAL",
+                    format!("'{}' is not a number of seconds", value),
+                )
+            })?,
+        Err(_) => Duration::from_secs(10),
+    };
+
+    let gzip = match vars.var("INFLUX_GZIP") {
+        Ok(value) => match value.trim() {
+            "1" | "true" => true,
+            "0" | "false" => false,
+            _ => {
+                return Err(ConfigError::new(
+                    "INFLUX_GZIP",
+                    format!("'{}' is not true or false", value),
+                ))
+            }
+        },
+        Err(_) => true,
+    };
+
+    Ok(InfluxConfig {
+        url,
+        token: vars.var("INFLUX_TOKEN").ok(),
+        batch_size,
+        flush_interval,
+        gzip,
+    })
+}
+
+/// Parse a comma separated list of sensors such as `1:0x76:bedroom,1:0x77:study`.
+fn parse_sensors(spec: &str) -> Result<Vec<SensorConfig>, String> {
+    let mut sensors: Vec<SensorConfig> = Vec::new();
+
+    for entry in spec.split(',').map(|entry| entry.trim()) {
+        if entry.is_empty() {
+            continue;
+        }
+
+        let parts: Vec<&str> = entry.split(':').collect();
+        if parts.len() < 2 || parts.len() > 4 {
+            return Err(format!(
+                "'{}' is not of the form <bus>:<address>[:<name>[:<location>]]",
+                entry
+            ));
+        }
+
+        let sensor = sensor_config(
+            parts[0],
+            parts[1],
+            parts.get(2).copied(),
+            parts.get(3).copied(),
+        )?;
+        add_sensor(&mut sensors, sensor, &format!("'{}'", entry))?;
+    }
+
+    Ok(sensors)
+}
+
+/// A sensor from its bus number, address in decimal or `0x` hex, and
+/// optional name and location.
+fn sensor_config(
+    bus: &str,
+    address: &str,
+    name: Option<&str>,
+    location: Option<&str>,
+) -> Result<SensorConfig, String> {
+    let bus = bus
+        .parse::<u8>()
+        .map_err(|_| format!("'{}' is not an i2c bus number", bus))?;
+
+    let address = match address.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => address.parse::<u8>(),
+    }
+    .map_err(|_| format!("'{}' is not an i2c address", address))?;
+
+    let name = match name {
+        Some(name) if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == '.') => {
+            return Err(format!("'{}' is not a valid metric name", name));
+        }
+        name => name.map(String::from),
+    };
+
+    Ok(SensorConfig {
+        path: PathBuf::from(format!("/dev/i2c-{}", bus)),
+        address,
+        name,
+        location: location
+            .filter(|location| !location.is_empty())
+            .map(String::from),
+    })
+}
+
+/// Add `sensor` to `sensors` unless its bus and address or its name are
+/// taken already, `what` naming it in the error.
+fn add_sensor(
+    sensors: &mut Vec<SensorConfig>,
+    sensor: SensorConfig,
+    what: &str,
+) -> Result<(), String> {
+    if sensors
+        .iter()
+        .any(|other| other.path == sensor.path && other.address == sensor.address)
+    {
+        return Err(format!("{} is listed twice", what));
+    }
+    if sensor.name.is_some() && sensors.iter().any(|other| other.name == sensor.name) {
+        return Err(format!("name in {} is used twice", what));
+    }
+
+    sensors.push(sensor);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard};
+
+    /// `Config::load` reads every variable, so tests that set some take
+    /// turns.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Hold the environment with only `vars` set among the settings.
+    fn with_env(vars: &[(&str, &str)]) -> MutexGuard<'static, ()> {
+        let guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        for (_, key) in SETTINGS {
+            env::remove_var(key);
+        }
+        for (key, value) in vars {
+            env::set_var(key, value);
+        }
+        guard
+    }
+
+    fn load_error() -> ConfigError {
+        match Config::load(None) {
+            Ok(_) => panic!("config accepted"),
+            Err(err) => err,
+        }
+    }
+
+    fn write_file(name: &str, text: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("bme-config-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn environment_overrides_file() {
+        let path = write_file(
+            "precedence",
+            "[graphite]\nurl = \"file.example.com:2003\"\nprefix = \"from_file\"\n",
+        );
+
+        let _env = with_env(&[("GRAPHITE_URL", "env.example.com:2003")]);
+        let graphite = Config::load(Some(&path)).unwrap().graphite.unwrap();
+        assert_eq!(graphite.url, "env.example.com:2003");
+        assert_eq!(graphite.prefix, "from_file");
+
+        // Empty counts as unset, leaving the file setting
+        env::set_var("GRAPHITE_URL", "");
+        let graphite = Config::load(Some(&path)).unwrap().graphite.unwrap();
+        assert_eq!(graphite.url, "file.example.com:2003");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn empty_values_are_unset() {
+        let path = write_file("empty", "[mqtt]\nurl = \"\"\n");
+
+        let _env = with_env(&[("GRAPHITE_URL", ""), ("INFLUX_URL", " ")]);
+        let config = Config::load(Some(&path)).unwrap();
+        assert!(config.graphite.is_none());
+        assert!(config.mqtt.is_none());
+        assert!(config.influx.is_none());
+        assert!(config.check_outputs().is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sensor_tables_match_string() {
+        let path = write_file(
+            "sensors",
+            "[[sensors]]\nbus = 1\naddress = \"0x76\"\nname = \"bedroom\"\n\n\
+             [[sensors]]\nbus = \"1\"\naddress = 119\nname = \"study\"\nlocation = \"upstairs\"\n",
+        );
+
+        let _env = with_env(&[]);
+        let from_file = Config::load(Some(&path)).unwrap().sensors;
+        let from_string = parse_sensors("1:0x76:bedroom, 1:119:study:upstairs").unwrap();
+        assert_eq!(from_file, from_string);
+        assert_eq!(
+            from_file[1],
+            SensorConfig {
+                path: PathBuf::from("/dev/i2c-1"),
+                address: 0x77,
+                name: Some(String::from("study")),
+                location: Some(String::from("upstairs")),
+            }
+        );
+
+        // Only the string form splits on ':' and ','
+        fs::write(
+            &path,
+            "[[sensors]]\nbus = 1\naddress = 0x77\nname = \"a,b\"\nlocation = \"floor:2\"\n",
+        )
+        .unwrap();
+        let sensors = Config::load(Some(&path)).unwrap().sensors;
+        assert_eq!(sensors[0].name.as_deref(), Some("a,b"));
+        assert_eq!(sensors[0].location.as_deref(), Some("floor:2"));
+
+        // The environment replaces the tables
+        env::set_var("SENSORS", "2:0x76");
+        let sensors = Config::load(Some(&path)).unwrap().sensors;
+        assert_eq!(sensors.len(), 1);
+        assert_eq!(sensors[0].path, PathBuf::from("/dev/i2c-2"));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_sensors() {
+        for spec in [
+            "1",
+            "x:0x77",
+            "1:0x100",
+            "1:0x77:my study",
+            "1:0x77:a.b",
+            "1:0x77,1:119",
+            "1:0x76:a,1:0x77:a",
+        ] {
+            assert!(parse_sensors(spec).is_err(), "{}", spec);
+        }
+
+        let table = |text: &str| text.parse::<Table>().unwrap()["sensors"].clone();
+        for text in [
+            "sensors = [{ bus = 1 }]",
+            "sensors = [{ bus = 1, address = 0x77, room = \"study\" }]",
+            "sensors = [{ bus = 1, address = 0x77, name = 1.5 }]",
+        ] {
+            let items = table(text);
+            assert!(
+                sensors_from_tables(items.as_array().unwrap()).is_err(),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_tags() {
+        assert_eq!(
+            parse_tags("room={location}, sensor=bme688-{address}").unwrap(),
+            [
+                (String::from("room"), String::from("{location}")),
+                (String::from("sensor"), String::from("bme688-{address}")),
+            ]
+        );
+
+        for spec in [
+            "room",
+            "name=study",
+            "ro;om=study",
+            "room=~study",
+            "room=a,room=b",
+            "room={output}",
+            "room={floor}",
+        ] {
+            assert!(parse_tags(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_templates() {
+        assert!(check_template("{prefix}.{host}.{sensor}.{output}").is_ok());
+
+        for template in ["{prefix}.{room}", "{prefix", "{prefix}. {output}", "a;b"] {
+            assert!(check_template(template).is_err(), "{}", template);
+        }
+
+        let _env = with_env(&[
+            ("GRAPHITE_URL", "localhost:2003"),
+            ("GRAPHITE_PATH_TEMPLATE", "{prefix}.{sensor}"),
+        ]);
+        let err = load_error();
+        assert_eq!(err.key, "GRAPHITE_PATH_TEMPLATE");
+    }
+
+    #[test]
+    fn rejects_bad_gas_labels() {
+        for labels in ["a,b,c,d,e", "a b", "a.b", "a,a", "voc", "coffee_accuracy"] {
+            let _env = with_env(&[("GAS_LABELS", labels)]);
+            let err = load_error();
+            assert_eq!(err.key, "GAS_LABELS", "{}", labels);
+        }
+
+        let _env = with_env(&[("GAS_LABELS", "clean_air,coffee")]);
+        let config = Config::load(None).unwrap();
+        assert_eq!(
+            config.gas_labels,
+            ["clean_air", "coffee", "gas_estimate_3", "gas_estimate_4"]
+        );
+    }
+
+    #[test]
+    fn to_toml_round_trips() {
+        let env_guard = with_env(&[
+            ("SENSORS", "1:0x76:bedroom,3:0x77:study:upstairs"),
+            ("GAS_LABELS", "clean_air,coffee"),
+            ("STATE_BACKUPS", "2"),
+            ("GRAPHITE_URL", "graphite.example.com:2004"),
+            ("GRAPHITE_PROTOCOL", "pickle"),
+            ("GRAPHITE_TAGS", "room={location}"),
+            ("PROMETHEUS_LISTEN", "127.0.0.1:9000"),
+            ("MQTT_URL", "localhost:1883"),
+            ("MQTT_USERNAME", "bme"),
+            ("INFLUX_URL", "udp://localhost:8089"),
+            ("GRAPHITE_RETRIES", "5"),
+        ]);
+        let config = Config::load(None).unwrap();
+        let text = config.to_toml();
+
+        let path = write_file("round-trip", &text);
+        drop(env_guard);
+        let _env = with_env(&[]);
+        let loaded = Config::load(Some(&path)).unwrap();
+        assert_eq!(loaded.to_toml(), text);
+        assert_eq!(loaded.sensors, config.sensors);
+        assert_eq!(loaded.sink_options("graphite").retries, 5);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+use log::{debug, info, warn};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// Time a client waits for the answer to a command.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Requests to the main loop, from signals and the control socket.
+pub enum Event {
+    Exit,
+    MeasureOnDemand,
+    /// Read the config again, answering with the outcome if asked over the
+    /// control socket.
+    Reload(Option<Sender<Result<(), String>>>),
+}
+
+/// Accepts commands on a Unix socket and passes them on to the main loop,
+/// one line per command: `reload` or `measure`. Every command is answered
+/// with `ok` or `error: <reason>`.
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    pub fn listen(path: &Path, event_tx: Sender<Event>) -> Result<ControlSocket, Error> {
+        // A socket left behind by a crash would make bind fail
+        if path.exists() && UnixStream::connect(path).is_err() {
+            std::fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+
+        info!("Listening for commands on {}", path.display());
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = answer(stream, &event_tx) {
+                            debug!("Failed to answer control command: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("Failed to accept control connection: {}", e),
+                }
+            }
+        });
+
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn answer(stream: UnixStream, event_tx: &Sender<Event>) -> Result<(), Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut line = String::new();
+    BufReader::new(stream.try_clone()?).read_line(&mut line)?;
+
+    let result = match line.trim() {
+        "reload" => {
+            let (reply_tx, reply_rx) = channel();
+            event_tx
+                .send(Event::Reload(Some(reply_tx)))
+                .map_err(|_| String::from("stopping"))
+                .and_then(|_| {
+                    reply_rx
+                        .recv_timeout(REPLY_TIMEOUT)
+                        .unwrap_or_else(|_| Err(String::from("no answer")))
+                })
+        }
+        "measure" => event_tx
+            .send(Event::MeasureOnDemand)
+            .map_err(|_| String::from("stopping")),
+        command => Err(format!("unknown command '{}'", command)),
+    };
+
+    let mut stream = stream;
+    match result {
+        Ok(()) => writeln!(stream, "ok"),
+        Err(message) => writeln!(stream, "error: {}", message),
+    }
+}
+
+/// Send `command` to the program listening on `path` and wait for the
+/// answer.
+pub fn send(path: &Path, command: &str) -> Result<(), Error> {
+    let mut stream = UnixStream::connect(path)
+        .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT + Duration::from_secs(5)))?;
+
+    writeln!(stream, "{}", command)?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+
+    match reply.trim() {
+        "ok" => Ok(()),
+        reply => Err(Error::new(
+            ErrorKind::Other,
+            reply.strip_prefix("error: ").unwrap_or(reply).to_string(),
+        )),
+    }
+}
@@ -0,0 +1,88 @@
+use crate::sink::{Reading, Sink};
+use log::info;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Error, Write};
+use std::path::Path;
+
+/// Appends every reading to a file as one JSON object per line.
+pub struct Appender {
+    writer: BufWriter<File>,
+}
+
+impl Appender {
+    pub fn open(path: &Path) -> Result<Appender, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        info!("Writing readings to {}", path.display());
+
+        Ok(Appender {
+            writer: BufWriter::new(file),
+        })
+    }
+}
+
+impl Sink for Appender {
+    fn send(&mut self, reading: &Reading) -> Result<(), Error> {
+        writeln!(self.writer, "{}", to_json(reading))?;
+        // Flush every line so a crash only loses the current reading
+        self.writer.flush()
+    }
+}
+
+/// e.g. `{"timestamp":1700000000000000000,"sensor":"study","bus":"i2c-1","address":119,"outputs":{"static_iaq":{"value":50,"accuracy":1}}}`
+fn to_json(reading: &Reading) -> String {
+    let mut json = format!(
+        "{{\"timestamp\":{},\"sensor\":\"{}\",\"bus\":\"{}\",\"address\":{}",
+        reading.timestamp,
+        escape(&reading.sensor.name),
+        escape(&reading.sensor.bus),
+        reading.sensor.address
+    );
+    if let Some(location) = reading.sensor.location.as_ref() {
+        json.push_str(&format!(",\"location\":\"{}\"", escape(location)));
+    }
+
+    json.push_str(",\"outputs\":{");
+    let mut first = true;
+    for output in reading.outputs.iter() {
+        if !first {
+            json.push(',');
+        }
+        first = false;
+
+        // JSON has no NaN or infinity
+        if output.value.is_finite() {
+            json.push_str(&format!(
+                "\"{}\":{{\"value\":{}",
+                output.kind.name(),
+                output.value
+            ));
+        } else {
+            json.push_str(&format!("\"{}\":{{\"value\":null", output.kind.name()));
+        }
+        if let Some(accuracy) = output.accuracy {
+            json.push_str(&format!(",\"accuracy\":{}", accuracy));
+        }
+        json.push('}');
+    }
+    json.push_str("}}");
+
+    json
+}
+
+/// Escape a string for a JSON string literal.
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
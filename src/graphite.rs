@@ -1,79 +1,793 @@
-use crate::bsec::{bsec_virtual_sensor_t, bsec_output_t};
-use log::debug;
+use crate::config::{GraphiteConfig, GraphiteProtocol, TlsConfig};
+use crate::sink::{OutputKind, Reading, SensorId, Sink};
+use crate::spool::Spool;
+use log::{debug, info, warn};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
 use std::{
-    io::{Error, ErrorKind, Write},
-    net::TcpStream,
+    collections::hash_map::RandomState,
+    collections::VecDeque,
+    convert::TryFrom,
+    hash::{BuildHasher, Hasher},
+    io::{Error, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::atomic::{AtomicU64, Ordering},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
-#[derive(Default)]
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// First delay between reconnects, doubled after every failure up to the
+/// configured maximum.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Largest datagram sent over UDP, to stay below the usual MTU.
+const MAX_DATAGRAM_BYTES: usize = 1400;
+
+/// Lines kept in memory while the server is unreachable and no spool is
+/// configured, older lines are dropped.
+const MAX_QUEUED_LINES: usize = 10_000;
+
+enum Connection {
+    Tcp(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+    /// Connected to the server address, so sends need no address.
+    Udp(UdpSocket),
+}
+
+impl Connection {
+    fn socket(&self) -> Option<&TcpStream> {
+        match self {
+            Connection::Tcp(stream) => Some(stream),
+            Connection::Tls(stream) => Some(stream.get_ref()),
+            Connection::Udp(_) => None,
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buffer),
+            Connection::Tls(stream) => stream.read(buffer),
+            Connection::Udp(_) => Err(Error::new(ErrorKind::Unsupported, "UDP socket")),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, Error> {
+        match self {
+            Connection::Tcp(stream) => stream.write(bytes),
+            Connection::Tls(stream) => stream.write(bytes),
+            Connection::Udp(socket) => socket.send(bytes),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+            Connection::Udp(_) => Ok(()),
+        }
+    }
+}
+
+/// Client side TLS settings, loaded once and used for every connect.
+struct Tls {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+}
+
+impl Tls {
+    fn load(config: &TlsConfig) -> Result<Tls, Error> {
+        let invalid = |e: &dyn std::fmt::Display, path: &std::path::Path| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("{}: {}", path.display(), e),
+            )
+        };
+
+        let mut roots = RootCertStore::empty();
+        let ca_certs = CertificateDer::pem_file_iter(&config.ca_file)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| invalid(&e, &config.ca_file))?;
+        let (added, _) = roots.add_parsable_certificates(ca_certs);
+        if added == 0 {
+            return Err(invalid(&"no CA certificates found", &config.ca_file));
+        }
+
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+
+        let client_config = match (config.cert_file.as_ref(), config.key_file.as_ref()) {
+            (Some(cert_file), Some(key_file)) => {
+                let certs = CertificateDer::pem_file_iter(cert_file)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| invalid(&e, cert_file))?;
+                let key =
+                    PrivateKeyDer::from_pem_file(key_file).map_err(|e| invalid(&e, key_file))?;
+                builder
+                    .with_client_auth_cert(certs, key)
+                    .map_err(|e| invalid(&e, cert_file))?
+            }
+            _ => builder.with_no_client_auth(),
+        };
+
+        let server_name = ServerName::try_from(config.server_name.clone()).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid TLS server name {}: {}", config.server_name, e),
+            )
+        })?;
+
+        Ok(Tls {
+            config: Arc::new(client_config),
+            server_name,
+        })
+    }
+
+    /// Wrap a new TCP connection and complete the handshake, so a bad
+    /// certificate fails the connect rather than the first send.
+    fn connect(&self, socket: TcpStream) -> Result<Connection, Error> {
+        socket.set_read_timeout(Some(WRITE_TIMEOUT))?;
+
+        let connection = ClientConnection::new(self.config.clone(), self.server_name.clone())
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let mut stream = StreamOwned::new(connection, socket);
+
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock)?;
+        }
+
+        Ok(Connection::Tls(Box::new(stream)))
+    }
+}
+
 pub struct State {
     url: String,
-    connection: Option<TcpStream>,
+    protocol: GraphiteProtocol,
+    tls: Option<Tls>,
+    connection: Option<Connection>,
 }
 
 impl State {
     pub fn reconnect(&mut self) -> Result<(), Error> {
         debug!("Trying to reconnect...");
-        let connection = TcpStream::connect(self.url.as_str())?;
-        connection.set_nodelay(true)?;
-        connection.set_nonblocking(true)?;
-        self.connection = Some(connection);
-        Ok(())
+        self.connection = None;
+
+        let mut last_error = Error::new(ErrorKind::NotFound, "no address found");
+        for address in self.url.to_socket_addrs()? {
+            match connect(address, self.protocol, self.tls.as_ref()) {
+                Ok(connection) => {
+                    self.connection = Some(connection);
+                    return Ok(());
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
     }
 }
 
-pub fn init(url: &str) -> State {
-    if let Ok(connection) = TcpStream::connect(url) {
-        let _ = connection.set_nodelay(true);
-        let _ = connection.set_nonblocking(true);
-        return State {
-            url: String::from(url),
-            connection: Some(connection),
-        };
-    } else {
-        return State {
-                url: String::from(url),
-                connection: None,
+fn connect(
+    address: SocketAddr,
+    protocol: GraphiteProtocol,
+    tls: Option<&Tls>,
+) -> Result<Connection, Error> {
+    match protocol {
+        GraphiteProtocol::Plaintext | GraphiteProtocol::Pickle => {
+            let connection = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+            connection.set_nodelay(true)?;
+            connection.set_write_timeout(Some(WRITE_TIMEOUT))?;
+            match tls {
+                Some(tls) => tls.connect(connection),
+                None => Ok(Connection::Tcp(connection)),
             }
+        }
+        GraphiteProtocol::Udp => {
+            let local = if address.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let socket = UdpSocket::bind(local)?;
+            socket.connect(address)?;
+            Ok(Connection::Udp(socket))
+        }
     }
 }
 
-pub fn build_output(sensor_outputs: Vec<bsec_output_t>, timestamp: i64) -> String {
+pub fn init(config: &GraphiteConfig) -> Result<State, Error> {
+    let tls = match config.tls.as_ref() {
+        Some(tls_config) => Some(Tls::load(tls_config)?),
+        None => None,
+    };
+
+    let mut state = State {
+        url: config.url.clone(),
+        protocol: config.protocol,
+        tls,
+        connection: None,
+    };
+    if let Err(e) = state.reconnect() {
+        info!("Graphite server {} not reachable yet: {}", config.url, e);
+    }
+    Ok(state)
+}
+
+/// Name of an output in Graphite paths. Every output has one, so that no
+/// two outputs end up in the same series.
+fn output_name(kind: OutputKind, gas_labels: &[String]) -> String {
+    let name = match kind {
+        OutputKind::Iaq => "dynamic_iaq",
+        OutputKind::StaticIaq => "iaq",
+        OutputKind::Co2Equivalent => "co2",
+        OutputKind::BreathVocEquivalent => "voc",
+        OutputKind::RawTemperature => "raw_temperature",
+        OutputKind::RawPressure => "pressure",
+        OutputKind::RawHumidity => "raw_humidity",
+        OutputKind::RawGas => "gas_resistance",
+        OutputKind::StabilizationStatus => "stable",
+        OutputKind::RunInStatus => "run_in",
+        OutputKind::HeatCompensatedTemperature => "temperature",
+        OutputKind::HeatCompensatedHumidity => "humidity",
+        OutputKind::CompensatedGas => "compensated_gas",
+        OutputKind::GasPercentage => "gas_percentage",
+        OutputKind::GasEstimate(index) => return format!("gas.{}", sanitize(&gas_labels[index])),
+        OutputKind::RawGasIndex => "gas_index",
+    };
+    String::from(name)
+}
+
+/// Replace characters that would split a path node or a tag.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == ':' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Host name for the `{host}` placeholder.
+fn host_name() -> String {
+    let host = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| String::from("localhost"));
+    sanitize(host.trim())
+}
+
+/// Builds metric paths from `GRAPHITE_PATH_TEMPLATE` and, with
+/// `GRAPHITE_TAGS`, Graphite 1.1 tags, e.g.
+/// `iaq;room=study;sensor=bme688-0x77`.
+pub struct Naming {
+    path_template: String,
+    tags: Vec<(String, String)>,
+    prefix: String,
+    host: String,
+    gas_labels: Vec<String>,
+}
+
+impl Naming {
+    pub fn new(config: &GraphiteConfig, gas_labels: Vec<String>) -> Naming {
+        Naming {
+            path_template: config.path_template.clone(),
+            tags: config.tags.clone(),
+            prefix: config.prefix.clone(),
+            host: host_name(),
+            gas_labels,
+        }
+    }
+
+    /// Full series name of `output`, e.g. `iaq` or `iaq.accuracy`, of a
+    /// sensor. Empty path nodes, e.g. from an unset location, are left out.
+    pub fn name(&self, sensor: &SensorId, output: &str) -> String {
+        let address = format!("{:#04x}", sensor.address);
+        let location = sensor.location.as_deref().map(sanitize).unwrap_or_default();
+        let values = [
+            ("host", self.host.clone()),
+            ("sensor", sanitize(&sensor.name)),
+            ("bus", sanitize(&sensor.bus)),
+            ("address", address),
+            ("location", location),
+        ];
+
+        let mut path = self.path_template.clone();
+        for (key, value) in values.iter() {
+            path = path.replace(&format!("{{{}}}", key), value);
+        }
+        path = path
+            .replace("{prefix}", &self.prefix)
+            .replace("{output}", output);
+
+        let mut name: String = path
+            .split('.')
+            .filter(|node| !node.is_empty())
+            .collect::<Vec<&str>>()
+            .join(".");
+
+        for (tag, template) in self.tags.iter() {
+            let mut value = template.replace("{prefix}", &self.prefix);
+            for (key, replacement) in values.iter() {
+                value = value.replace(&format!("{{{}}}", key), replacement);
+            }
+            // Graphite rejects empty tag values
+            if !value.is_empty() {
+                name.push_str(&format!(";{}={}", tag, value));
+            }
+        }
+
+        name
+    }
+}
+
+/// Build Graphite plaintext lines for one reading. Outputs that carry a
+/// calibration status also get an `.accuracy` series, and IAQ and VOC values
+/// are left out while their accuracy is below `min_accuracy`.
+pub fn build_output(reading: &Reading, naming: &Naming, min_accuracy: u8) -> String {
     let mut metrics_string = String::from("");
 
-    for sensor in sensor_outputs {
-        let metric_name = match sensor.sensor_id as u32 {
-            bsec_virtual_sensor_t::BSEC_OUTPUT_STATIC_IAQ => "study.iaq",
-            bsec_virtual_sensor_t::BSEC_OUTPUT_STABILIZATION_STATUS => "study.stable",
-            bsec_virtual_sensor_t::BSEC_OUTPUT_SENSOR_HEAT_COMPENSATED_TEMPERATURE => {
-                "study.temperature"
-            }
-            bsec_virtual_sensor_t::BSEC_OUTPUT_SENSOR_HEAT_COMPENSATED_HUMIDITY => "study.humidity",
-            bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_PRESSURE => "study.pressure",
-            bsec_virtual_sensor_t::BSEC_OUTPUT_BREATH_VOC_EQUIVALENT => "study.voc",
-            bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_GAS => "study.gas_resistance",
-            _ => "study.unknown",
-        };
+    for output in reading.outputs.iter() {
+        let output_name = output_name(output.kind, &naming.gas_labels);
+        let metric_name = naming.name(&reading.sensor, &output_name);
+
+        let timestamp_secs = reading.timestamp / 1000 / 1000 / 1000;
+
+        if let Some(accuracy) = output.accuracy {
+            let accuracy_name = naming.name(&reading.sensor, &format!("{}.accuracy", output_name));
+            metrics_string.push_str(&*format!(
+                "{} {} {}\n",
+                accuracy_name, accuracy, timestamp_secs
+            ));
+
+            if output.below_accuracy(min_accuracy) {
+                debug!(
+                    "Skipping {} with accuracy {} below {}",
+                    metric_name, accuracy, min_accuracy
+                );
+                continue;
+            }
+        }
+
         metrics_string.push_str(&*format!(
             "{} {} {}\n",
-            metric_name,
-            sensor.signal,
-            timestamp / 1000 / 1000 / 1000
+            metric_name, output.value, timestamp_secs
         ));
     }
 
     metrics_string
 }
 
+/// Send plaintext lines in the configured protocol. Over TCP, a batch that
+/// fails halfway is sent again in full, which Graphite tolerates as it keeps
+/// one value per metric and timestamp.
 pub fn send_metrics(state: &mut State, metrics: &str) -> Result<(), Error> {
+    let protocol = state.protocol;
+
     match state.connection.as_mut() {
+        Some(Connection::Udp(socket)) => send_datagrams(socket, metrics),
         Some(connection) => {
-            connection.write(metrics.as_bytes())?;
-            connection.flush()?;
+            if peer_closed(connection)? {
+                return Err(Error::new(
+                    ErrorKind::ConnectionAborted,
+                    "server closed the connection",
+                ));
+            }
+
+            if protocol == GraphiteProtocol::Pickle {
+                write_fully(connection, &encode_pickle(metrics))
+            } else {
+                write_fully(connection, metrics.as_bytes())
+            }
+        }
+        None => Err(Error::new(ErrorKind::NotConnected, "not connected")),
+    }
+}
+
+/// Write all of `bytes`, continuing after short writes.
+fn write_fully(connection: &mut Connection, mut bytes: &[u8]) -> Result<(), Error> {
+    while !bytes.is_empty() {
+        match connection.write(bytes) {
+            Ok(0) => {
+                return Err(Error::new(
+                    ErrorKind::WriteZero,
+                    "connection closed while sending",
+                ))
+            }
+            Ok(written) => bytes = &bytes[written..],
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    connection.flush()
+}
+
+/// Send lines in as few datagrams as fit below `MAX_DATAGRAM_BYTES`. Lost
+/// datagrams cannot be noticed, so a server that is down is not an error.
+fn send_datagrams(socket: &UdpSocket, metrics: &str) -> Result<(), Error> {
+    let mut datagram = String::new();
+
+    for line in metrics.lines() {
+        if !datagram.is_empty() && datagram.len() + line.len() + 1 > MAX_DATAGRAM_BYTES {
+            send_datagram(socket, &datagram)?;
+            datagram.clear();
+        }
+        datagram.push_str(line);
+        datagram.push('\n');
+    }
+
+    if !datagram.is_empty() {
+        send_datagram(socket, &datagram)?;
+    }
+    Ok(())
+}
+
+fn send_datagram(socket: &UdpSocket, datagram: &str) -> Result<(), Error> {
+    match socket.send(datagram.as_bytes()) {
+        Ok(_) => Ok(()),
+        // An ICMP port unreachable for an earlier datagram
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            debug!("Graphite server not listening on UDP: {}", e);
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Encode plaintext lines as a pickled list of `(path, (timestamp, value))`
+/// tuples, prefixed with its length as carbon's pickle receiver expects.
+/// Lines that do not parse are left out.
+pub fn encode_pickle(metrics: &str) -> Vec<u8> {
+    // Pickle protocol 2, an empty list and a mark for APPENDS
+    let mut pickle = vec![0x80, 0x02, b']', b'('];
+
+    for line in metrics.lines() {
+        let mut parts = line.split(' ');
+        let (path, value, timestamp) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(path), Some(value), Some(timestamp)) => (path, value, timestamp),
+            _ => continue,
+        };
+        let (value, timestamp) = match (value.parse::<f64>(), timestamp.parse::<i64>()) {
+            (Ok(value), Ok(timestamp)) => (value, timestamp),
+            _ => continue,
+        };
+
+        // BINUNICODE
+        pickle.push(b'X');
+        pickle.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        pickle.extend_from_slice(path.as_bytes());
+
+        // BININT while it fits, BINFLOAT after 2038
+        match i32::try_from(timestamp) {
+            Ok(timestamp) => {
+                pickle.push(b'J');
+                pickle.extend_from_slice(&timestamp.to_le_bytes());
+            }
+            Err(_) => {
+                pickle.push(b'G');
+                pickle.extend_from_slice(&(timestamp as f64).to_be_bytes());
+            }
         }
-        None => {
-            return Err(Error::new(ErrorKind::Other, "not connected"));
+
+        // BINFLOAT, then TUPLE2 twice
+        pickle.push(b'G');
+        pickle.extend_from_slice(&value.to_be_bytes());
+        pickle.push(0x86);
+        pickle.push(0x86);
+    }
+
+    // APPENDS, STOP
+    pickle.push(b'e');
+    pickle.push(b'.');
+
+    let mut frame = Vec::with_capacity(pickle.len() + 4);
+    frame.extend_from_slice(&(pickle.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&pickle);
+    frame
+}
+
+/// Whether the server closed its side of the connection. Carbon never sends
+/// anything but TLS records, so a readable socket means it hung up, and
+/// writes would only fail once the kernel gives up on them.
+fn peer_closed(connection: &mut Connection) -> Result<bool, Error> {
+    let socket = match connection.socket() {
+        Some(socket) => socket,
+        None => return Ok(false),
+    };
+    socket.set_nonblocking(true)?;
+
+    let mut buffer = [0u8; 512];
+    let result = loop {
+        match connection.read(&mut buffer) {
+            Ok(0) => break Ok(true),
+            // TLS without close_notify
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break Ok(true),
+            // Unexpected data, discard it
+            Ok(_) => continue,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(false),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
         }
+    };
+
+    if let Some(socket) = connection.socket() {
+        socket.set_nonblocking(false)?;
     }
+    result
+}
+
+/// Counters of the Graphite client, shared with the Prometheus exporter.
+#[derive(Clone, Default)]
+pub struct Stats {
+    /// Points waiting in the spool.
+    pub spooled_points: Arc<AtomicU64>,
+    /// Points dropped for age or because the spool or queue was full.
+    pub dropped_points: Arc<AtomicU64>,
+    pub sent_points: Arc<AtomicU64>,
+    pub sent_batches: Arc<AtomicU64>,
+    /// Time spent sending all batches, in microseconds.
+    pub batch_micros: Arc<AtomicU64>,
+    /// Failed connects and sends.
+    pub errors: Arc<AtomicU64>,
+}
+
+/// Send one batch of lines and count it.
+fn send_batch(state: &mut State, stats: &Stats, batch: &str) -> Result<(), Error> {
+    let start = Instant::now();
+
+    send_metrics(state, batch)?;
+
+    let elapsed = start.elapsed();
+    let points = batch.lines().count();
+
+    stats
+        .sent_points
+        .fetch_add(points as u64, Ordering::Relaxed);
+    stats.sent_batches.fetch_add(1, Ordering::Relaxed);
+    stats
+        .batch_micros
+        .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+
+    debug!("Sent {} points to Graphite in {:?}", points, elapsed);
     Ok(())
 }
+
+/// Exponential backoff between reconnects, with jitter so that many
+/// loggers do not hit a recovering server at once.
+struct Backoff {
+    delay: Duration,
+    max: Duration,
+    next_attempt: Instant,
+}
+
+impl Backoff {
+    fn new(max: Duration) -> Backoff {
+        Backoff {
+            delay: MIN_BACKOFF,
+            max,
+            next_attempt: Instant::now(),
+        }
+    }
+
+    fn ready(&self) -> bool {
+        Instant::now() >= self.next_attempt
+    }
+
+    /// Wait between half and all of the current delay, then double it.
+    fn failed(&mut self) -> Duration {
+        let wait = self.delay / 2 + jitter(self.delay / 2);
+        self.next_attempt = Instant::now() + wait;
+        self.delay = (self.delay * 2).min(self.max);
+        wait
+    }
+
+    fn succeeded(&mut self) {
+        self.delay = MIN_BACKOFF;
+    }
+}
+
+/// A random duration up to `max`.
+fn jitter(max: Duration) -> Duration {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u32(now.subsec_nanos());
+    }
+    max.mul_f64((hasher.finish() % 1000) as f64 / 1000.0)
+}
+
+/// Sends readings to a Graphite server in the plaintext protocol, in
+/// batches of up to `batch_size` lines. While the server is unreachable,
+/// lines wait in memory, or on disk when a spool is configured, and are sent
+/// in order once it is back.
+pub struct Client {
+    state: State,
+    naming: Naming,
+    min_accuracy: u8,
+    batch_size: usize,
+    /// Lines not sent yet, oldest first.
+    queue: VecDeque<String>,
+    spool: Option<Spool>,
+    backoff: Backoff,
+    /// Lines dropped from `queue`.
+    dropped: u64,
+    stats: Stats,
+}
+
+impl Client {
+    pub fn new(
+        config: &GraphiteConfig,
+        gas_labels: Vec<String>,
+        min_accuracy: u8,
+    ) -> Result<Client, Error> {
+        let spool = match config.spool.clone() {
+            Some(spool_config) => Some(Spool::open(spool_config)?),
+            None => None,
+        };
+
+        let client = Client {
+            state: init(config)?,
+            naming: Naming::new(config, gas_labels),
+            min_accuracy,
+            batch_size: config.batch_size,
+            queue: VecDeque::new(),
+            spool,
+            backoff: Backoff::new(config.max_backoff),
+            dropped: 0,
+            stats: Stats::default(),
+        };
+        client.update_stats();
+
+        Ok(client)
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats.clone()
+    }
+
+    fn update_stats(&self) {
+        let (spooled, spool_dropped) = match self.spool.as_ref() {
+            Some(spool) => (spool.points(), spool.dropped()),
+            None => (0, 0),
+        };
+        self.stats.spooled_points.store(spooled, Ordering::Relaxed);
+        self.stats
+            .dropped_points
+            .store(spool_dropped + self.dropped, Ordering::Relaxed);
+    }
+
+    /// Send the spool and the queue if the server can be reached, and put
+    /// aside what could not be sent.
+    fn flush(&mut self) -> Result<(), Error> {
+        let spooled = matches!(self.spool.as_ref(), Some(spool) if !spool.is_empty());
+
+        if (!self.queue.is_empty() || spooled)
+            && (self.state.connection.is_some() || self.backoff.ready())
+        {
+            match self.send_all() {
+                Ok(()) => self.backoff.succeeded(),
+                Err(e) => {
+                    self.stats.errors.fetch_add(1, Ordering::Relaxed);
+                    self.state.connection = None;
+                    let wait = self.backoff.failed();
+                    warn!(
+                        "Failed to send metrics to Graphite, retrying in {:.1}s: {}",
+                        wait.as_secs_f64(),
+                        e
+                    );
+                }
+            }
+        }
+
+        if self.state.connection.is_none() {
+            self.put_aside()?;
+        }
+
+        Ok(())
+    }
+
+    /// Send the spool, then the queue, a batch at a time.
+    fn send_all(&mut self) -> Result<(), Error> {
+        if self.state.connection.is_none() {
+            self.state.reconnect()?;
+            info!("Connected to Graphite at {}", self.state.url);
+        }
+
+        if let Some(spool) = self.spool.as_mut() {
+            let state = &mut self.state;
+            let stats = &self.stats;
+            spool.drain(self.batch_size, |batch| send_batch(state, stats, batch))?;
+        }
+
+        while !self.queue.is_empty() {
+            let count = self.batch_size.min(self.queue.len());
+            let mut batch = String::new();
+            for line in self.queue.iter().take(count) {
+                batch.push_str(line);
+                batch.push('\n');
+            }
+
+            send_batch(&mut self.state, &self.stats, &batch)?;
+            self.queue.drain(..count);
+        }
+
+        Ok(())
+    }
+
+    /// Move the queue to the spool while disconnected, or drop the oldest
+    /// lines once too many are waiting without one.
+    fn put_aside(&mut self) -> Result<(), Error> {
+        match self.spool.as_mut() {
+            Some(spool) if !self.queue.is_empty() => {
+                let mut lines = String::new();
+                for line in self.queue.drain(..) {
+                    lines.push_str(&line);
+                    lines.push('\n');
+                }
+                spool.push(&lines)?;
+            }
+            Some(_) => {}
+            None => {
+                if self.queue.len() > MAX_QUEUED_LINES {
+                    let excess = self.queue.len() - MAX_QUEUED_LINES;
+                    self.queue.drain(..excess);
+                    self.dropped += excess as u64;
+                    warn!("Graphite unreachable, dropped {} oldest points", excess);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink for Client {
+    fn send(&mut self, reading: &Reading) -> Result<(), Error> {
+        let metrics_string = build_output(reading, &self.naming, self.min_accuracy);
+        self.queue.extend(metrics_string.lines().map(String::from));
+
+        // Connected or not, new lines queue up behind older ones to keep them in order
+        if self.queue.len() >= self.batch_size || self.state.connection.is_none() {
+            self.flush()?;
+        }
+
+        self.update_stats();
+        Ok(())
+    }
+
+    /// Send what is queued at least every tick, and retry the spool while
+    /// the server is unreachable.
+    fn tick(&mut self) -> Result<(), Error> {
+        if let Some(spool) = self.spool.as_mut() {
+            spool.expire();
+        }
+        let result = self.flush();
+        self.update_stats();
+        result
+    }
+
+    fn close(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Failed to spool Graphite points: {}", e);
+        }
+        self.update_stats();
+
+        if !self.queue.is_empty() {
+            warn!("Dropping {} unsent Graphite points.", self.queue.len());
+        }
+        if let Some(spool) = self.spool.as_ref() {
+            if !spool.is_empty() {
+                info!(
+                    "{} Graphite points left in the spool for the next start.",
+                    spool.points()
+                );
+            }
+        }
+    }
+}
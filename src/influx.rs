@@ -0,0 +1,387 @@
+use crate::config::InfluxConfig;
+use crate::sink::{OutputKind, Reading, Sink};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{debug, error, info, warn};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+const MEASUREMENT: &str = "bme";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Batches kept while the server is unreachable, older points are dropped.
+const MAX_PENDING_BATCHES: usize = 10;
+
+/// Field keys of the outputs other than the gas estimates, which are keyed
+/// by their label and must not take one of these.
+pub const FIELD_KEYS: [&str; 15] = [
+    "iaq",
+    "static_iaq",
+    "co2",
+    "voc",
+    "raw_temperature",
+    "pressure",
+    "raw_humidity",
+    "gas_resistance",
+    "stabilization",
+    "run_in_status",
+    "temperature",
+    "humidity",
+    "compensated_gas",
+    "gas_percentage",
+    "raw_gas_index",
+];
+
+/// Field key of an output in the line protocol.
+fn field_key(kind: OutputKind, gas_labels: &[String]) -> &str {
+    match kind {
+        OutputKind::Iaq => "iaq",
+        OutputKind::StaticIaq => "static_iaq",
+        OutputKind::Co2Equivalent => "co2",
+        OutputKind::BreathVocEquivalent => "voc",
+        OutputKind::RawPressure => "pressure",
+        OutputKind::RawGas => "gas_resistance",
+        OutputKind::StabilizationStatus => "stabilization",
+        OutputKind::HeatCompensatedTemperature => "temperature",
+        OutputKind::HeatCompensatedHumidity => "humidity",
+        OutputKind::GasEstimate(index) => &gas_labels[index],
+        _ => kind.name(),
+    }
+}
+
+/// Escape commas, spaces and equal signs in tag keys, tag values and field
+/// keys.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == ',' || c == ' ' || c == '=' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Encode one reading as a single line protocol point, with the BSEC
+/// timestamp in nanoseconds. Outputs that carry a calibration status also
+/// get an integer `<field>_accuracy` field, and IAQ and VOC values are left
+/// out while their accuracy is below `min_accuracy`.
+pub fn encode(reading: &Reading, gas_labels: &[String], min_accuracy: u8) -> Option<String> {
+    let mut fields: Vec<String> = Vec::new();
+
+    for output in reading.outputs.iter() {
+        let key = field_key(output.kind, gas_labels);
+
+        if let Some(accuracy) = output.accuracy {
+            fields.push(format!("{}_accuracy={}i", escape(key), accuracy));
+
+            if output.below_accuracy(min_accuracy) {
+                continue;
+            }
+        }
+
+        if output.value.is_finite() {
+            fields.push(format!("{}={}", escape(key), output.value));
+        }
+    }
+
+    if fields.is_empty() {
+        return None;
+    }
+
+    // Tags sorted by key, as InfluxDB prefers
+    let mut line = format!(
+        "{},address={:#04x},bus={}",
+        MEASUREMENT,
+        reading.sensor.address,
+        escape(&reading.sensor.bus)
+    );
+    if let Some(location) = reading.sensor.location.as_ref() {
+        line.push_str(&format!(",location={}", escape(location)));
+    }
+    line.push_str(&format!(",sensor={}", escape(&reading.sensor.name)));
+
+    line.push(' ');
+    line.push_str(&fields.join(","));
+    line.push_str(&format!(" {}", reading.timestamp));
+
+    Some(line)
+}
+
+enum Transport {
+    /// Every point is sent as its own datagram.
+    Udp { socket: UdpSocket, address: String },
+    Http {
+        /// `host:port` to connect to.
+        host: String,
+        /// Path and query of the write endpoint.
+        path: String,
+    },
+}
+
+/// Writes readings to InfluxDB over UDP, or in batches to the HTTP write API.
+pub struct Writer {
+    config: InfluxConfig,
+    gas_labels: Vec<String>,
+    min_accuracy: u8,
+    transport: Transport,
+    /// Points waiting for the current batch to fill up.
+    batch: Vec<String>,
+    /// Full batches the server did not accept yet.
+    pending: VecDeque<Vec<String>>,
+    next_flush: Instant,
+}
+
+impl Writer {
+    pub fn new(
+        config: InfluxConfig,
+        gas_labels: Vec<String>,
+        min_accuracy: u8,
+    ) -> Result<Writer, Error> {
+        let transport = if let Some(address) = config.url.strip_prefix("udp://") {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            Transport::Udp {
+                socket,
+                address: String::from(address),
+            }
+        } else if let Some(url) = config.url.strip_prefix("http://") {
+            let (host, path) = match url.find('/') {
+                Some(index) => (&url[..index], &url[index..]),
+                None => (url, "/write"),
+            };
+            let host = if host.contains(':') {
+                String::from(host)
+            } else {
+                format!("{}:80", host)
+            };
+            Transport::Http {
+                host,
+                path: String::from(path),
+            }
+        } else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unsupported InfluxDB URL {}", config.url),
+            ));
+        };
+
+        info!("Writing readings to InfluxDB at {}", config.url);
+
+        let next_flush = Instant::now() + config.flush_interval;
+
+        Ok(Writer {
+            config,
+            gas_labels,
+            min_accuracy,
+            transport,
+            batch: Vec::new(),
+            pending: VecDeque::new(),
+            next_flush,
+        })
+    }
+
+    pub fn write(&mut self, reading: &Reading) -> Result<(), Error> {
+        let line = match encode(reading, &self.gas_labels, self.min_accuracy) {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+
+        if let Transport::Udp { socket, address } = &self.transport {
+            socket.send_to(line.as_bytes(), address.as_str())?;
+            return Ok(());
+        }
+
+        self.batch.push(line);
+
+        if self.batch.len() >= self.config.batch_size {
+            // The point is queued now, a failed batch is retried on the next flush
+            if let Err(e) = self.flush() {
+                warn!("Failed to send points to InfluxDB: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the current batch and any batches left over from earlier
+    /// failures, oldest first.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if !self.batch.is_empty() {
+            let batch = std::mem::take(&mut self.batch);
+            self.pending.push_back(batch);
+        }
+
+        while self.pending.len() > MAX_PENDING_BATCHES {
+            if let Some(dropped) = self.pending.pop_front() {
+                warn!("InfluxDB unreachable, dropped {} points", dropped.len());
+            }
+        }
+
+        while let Some(batch) = self.pending.front() {
+            let body = batch.join("\n");
+            self.post(body.as_bytes())?;
+            debug!("Sent {} points to InfluxDB", batch.len());
+            self.pending.pop_front();
+        }
+
+        Ok(())
+    }
+
+    fn post(&self, body: &[u8]) -> Result<(), Error> {
+        let (host, path) = match &self.transport {
+            Transport::Http { host, path } => (host, path),
+            Transport::Udp { .. } => return Ok(()),
+        };
+
+        let body = if self.config.gzip {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()?
+        } else {
+            body.to_vec()
+        };
+
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n",
+            path,
+            host,
+            body.len()
+        );
+        if self.config.gzip {
+            request.push_str("Content-Encoding: gzip\r\n");
+        }
+        if let Some(token) = self.config.token.as_ref() {
+            request.push_str(&format!("Authorization: Token {}\r\n", token));
+        }
+        request.push_str("\r\n");
+
+        let mut connection = connect(host)?;
+        connection.set_read_timeout(Some(IO_TIMEOUT))?;
+        connection.set_write_timeout(Some(IO_TIMEOUT))?;
+        connection.write_all(request.as_bytes())?;
+        connection.write_all(&body)?;
+        connection.flush()?;
+
+        let mut status_line = String::new();
+        BufReader::new(connection).read_line(&mut status_line)?;
+
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            Some(status) if status.starts_with('4') && status != "429" => {
+                // The server will never accept these points, don't retry them
+                error!("InfluxDB rejected points: {}", status_line.trim());
+                Ok(())
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unexpected response '{}'", status_line.trim()),
+            )),
+        }
+    }
+}
+
+/// Connect to the first address of `host` that answers, so an unreachable
+/// server does not block the sink thread for minutes.
+fn connect(host: &str) -> Result<TcpStream, Error> {
+    let mut last_error = Error::new(ErrorKind::NotFound, "no address found");
+    for address in host.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(connection) => return Ok(connection),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+impl Sink for Writer {
+    fn send(&mut self, reading: &Reading) -> Result<(), Error> {
+        self.write(reading)
+    }
+
+    /// Flush the batch at least every `flush_interval`.
+    fn tick(&mut self) -> Result<(), Error> {
+        if Instant::now() < self.next_flush {
+            return Ok(());
+        }
+        self.next_flush = Instant::now() + self.config.flush_interval;
+        self.flush()
+    }
+
+    fn close(&mut self) {
+        info!("Sending remaining points to InfluxDB.");
+        if let Err(e) = self.flush() {
+            error!("Failed to send points to InfluxDB: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{Output, SensorId};
+
+    #[test]
+    fn encodes_both_iaq_outputs() {
+        let reading = Reading {
+            sensor: SensorId {
+                name: String::from("study"),
+                bus: String::from("i2c-1"),
+                address: 0x77,
+                location: None,
+            },
+            timestamp: 1_700_000_000_000_000_000,
+            outputs: vec![
+                Output {
+                    kind: OutputKind::Iaq,
+                    value: 60.0,
+                    accuracy: Some(3),
+                },
+                Output {
+                    kind: OutputKind::StaticIaq,
+                    value: 50.0,
+                    accuracy: Some(3),
+                },
+                Output {
+                    kind: OutputKind::GasEstimate(0),
+                    value: 0.5,
+                    accuracy: Some(3),
+                },
+            ],
+        };
+        let gas_labels = vec![String::from("coffee")];
+
+        assert_eq!(
+            encode(&reading, &gas_labels, 0).unwrap(),
+            "bme,address=0x77,bus=i2c-1,sensor=study iaq_accuracy=3i,iaq=60,\
+             static_iaq_accuracy=3i,static_iaq=50,coffee_accuracy=3i,coffee=0.5 \
+             1700000000000000000"
+        );
+    }
+
+    #[test]
+    fn field_keys_are_complete() {
+        let kinds = [
+            OutputKind::Iaq,
+            OutputKind::StaticIaq,
+            OutputKind::Co2Equivalent,
+            OutputKind::BreathVocEquivalent,
+            OutputKind::RawTemperature,
+            OutputKind::RawPressure,
+            OutputKind::RawHumidity,
+            OutputKind::RawGas,
+            OutputKind::StabilizationStatus,
+            OutputKind::RunInStatus,
+            OutputKind::HeatCompensatedTemperature,
+            OutputKind::HeatCompensatedHumidity,
+            OutputKind::CompensatedGas,
+            OutputKind::GasPercentage,
+            OutputKind::RawGasIndex,
+        ];
+
+        let keys: Vec<&str> = kinds.iter().map(|kind| field_key(*kind, &[])).collect();
+        assert_eq!(keys, FIELD_KEYS);
+    }
+}
@@ -0,0 +1,136 @@
+use log::kv::{self, Key, Value, VisitSource};
+use log::{Level, Log, Metadata, Record};
+use std::io::Error;
+use std::os::unix::net::UnixDatagram;
+use std::sync::OnceLock;
+
+/// Where journald accepts log entries in its native protocol.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Logs to stderr through `env_logger` until `use_journal` is called, then
+/// to journald. `RUST_LOG` filters both.
+struct Logger {
+    stderr: env_logger::Logger,
+    journal: OnceLock<UnixDatagram>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Install the logger, logging to stderr.
+pub fn init() {
+    let logger = LOGGER.get_or_init(|| Logger {
+        stderr: env_logger::Builder::from_default_env().build(),
+        journal: OnceLock::new(),
+    });
+
+    log::set_max_level(logger.stderr.filter());
+    log::set_logger(logger).expect("Logger already set");
+}
+
+/// Send the log to journald from now on, with the key-value pairs of a log
+/// record, e.g. the sensor address or BSEC return code, as journal fields.
+pub fn use_journal() -> Result<(), Error> {
+    let logger = LOGGER.get().expect("Logger not initialized");
+
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(JOURNAL_SOCKET)?;
+
+    let _ = logger.journal.set(socket);
+
+    Ok(())
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.stderr.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.stderr.matches(record) {
+            return;
+        }
+
+        let journal = match self.journal.get() {
+            Some(journal) => journal,
+            None => return self.stderr.log(record),
+        };
+
+        if let Err(e) = journal.send(&journal_entry(record)) {
+            // Better on stderr, which systemd also collects, than lost
+            eprintln!("Cannot log to journald: {}", e);
+            self.stderr.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+    }
+}
+
+/// A log record in the native journal protocol, see systemd's
+/// `sd_journal_send`.
+fn journal_entry(record: &Record) -> Vec<u8> {
+    let priority = match record.level() {
+        Level::Error => "3",
+        Level::Warn => "4",
+        Level::Info => "6",
+        Level::Debug | Level::Trace => "7",
+    };
+
+    let mut entry = Vec::new();
+    add_field(&mut entry, "MESSAGE", &record.args().to_string());
+    add_field(&mut entry, "PRIORITY", priority);
+    add_field(&mut entry, "SYSLOG_IDENTIFIER", env!("CARGO_PKG_NAME"));
+    add_field(&mut entry, "TARGET", record.target());
+    if let Some(file) = record.file() {
+        add_field(&mut entry, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        add_field(&mut entry, "CODE_LINE", &line.to_string());
+    }
+
+    let _ = record.key_values().visit(&mut FieldVisitor(&mut entry));
+
+    entry
+}
+
+struct FieldVisitor<'a>(&'a mut Vec<u8>);
+
+impl<'kvs> VisitSource<'kvs> for FieldVisitor<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        add_field(self.0, &field_name(key.as_str()), &value.to_string());
+        Ok(())
+    }
+}
+
+/// Journal field names are upper case letters, digits and underscores, and
+/// cannot start with an underscore or digit.
+fn field_name(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| match c {
+            'a'..='z' => c.to_ascii_uppercase(),
+            'A'..='Z' | '0'..='9' => c,
+            _ => '_',
+        })
+        .collect();
+    let name = name.trim_start_matches(|c: char| c == '_' || c.is_ascii_digit());
+    if name.is_empty() {
+        String::from("FIELD")
+    } else {
+        name.to_string()
+    }
+}
+
+/// `NAME=value`, or a length prefixed value if it has a newline in it.
+fn add_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
@@ -1,250 +1,598 @@
-use bme::I2cDriver;
-use bme68x_rust::{Device, DeviceConfig, Filter, GasHeaterConfig, Interface, Odr};
-use bsec::BSEC_SAMPLE_RATE_LP;
-use chrono::{Local, NaiveDateTime, Utc};
+use bme::Reopen;
+use bme68x_rust::Interface;
+use chrono::Local;
+use cli::Command;
+use config::Config;
+use control::{ControlSocket, Event};
 use dotenvy::dotenv;
 use log::{debug, error, info, warn};
+use mock::MockI2c;
+use prometheus::Exporter;
+use sensor::Sensor;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::iterator::Signals;
+use signal_hook::low_level;
+use sink::{OutputKind, Reading, SensorId, Sinks};
 use std::cmp::max;
-use std::path::Path;
-use std::sync::mpsc::{channel, TryRecvError};
-use std::time::Duration;
-use std::{env, fs, thread};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+use std::{env, process, thread};
 mod bme;
 mod bsec;
+mod cli;
+mod config;
+mod control;
+mod file;
 mod graphite;
+mod influx;
+mod logging;
+mod mock;
+mod mqtt;
+mod prometheus;
+mod recording;
+mod sensor;
+mod sink;
+mod spool;
+mod state;
+mod systemd;
+
+/// Longest time from a stop signal to exit, after which the program exits
+/// without waiting for a hung measurement or output.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time the outputs get to send or spool their queues on exit.
+const SINK_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
 
 fn main() -> std::io::Result<()> {
-    env_logger::init();
-    dotenv().expect(".env file not found");
+    logging::init();
+
+    let args = cli::parse_args().unwrap_or_else(|message| {
+        eprintln!("{}\n\n{}", message, cli::USAGE);
+        process::exit(2);
+    });
+
+    // Variables in .env are added to the environment, without replacing any
+    match dotenv() {
+        Ok(path) => info!("Loaded variables from {}", path.display()),
+        Err(e) if e.not_found() => {}
+        Err(e) => {
+            eprintln!("Cannot read .env: {}", e);
+            process::exit(1);
+        }
+    }
     for (key, value) in env::vars() {
         debug!("{key}: {value}");
     }
 
-    let mut run_loop = true;
+    let config = Config::load(args.config_file.as_deref()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
 
-    // Setup new thread to send data to server
+    if args.check_config {
+        if let Err(e) = config.check_outputs() {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        match args.config_file.as_ref() {
+            Some(path) => println!("# Settings from {} and the environment", path.display()),
+            None => println!("# Settings from the environment"),
+        }
+        println!("# Passwords and tokens are not shown\n");
+        print!("{}", config.to_toml());
+        return Ok(());
+    }
 
-    let (data_tx, data_rx) = channel::<String>();
+    let result = match args.command {
+        Command::Run => daemon(config, args.config_file),
+        Command::Scan => cli::scan(&config),
+        Command::Read => cli::read(&config),
+        Command::BsecVersion => cli::bsec_version(),
+        Command::State(action, files) => cli::state(&config, action, &files),
+        Command::Reload => cli::control(&config, "reload"),
+        Command::Measure => cli::control(&config, "measure"),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 
-    thread::spawn(move || {
-        let graphite_url = env::var("GRAPHITE_URL").expect("Missing config for GRAPHITE_URL");
-
-        let mut graphite_state = graphite::init(graphite_url.as_str());
-
-        loop {
-            let data_res = data_rx.try_recv();
-            match data_res {
-                Ok(data) => 'sendLine: loop {
-                    let send_res = graphite::send_metrics(&mut graphite_state, data.as_str());
-                    match send_res {
-                        Ok(_) => {
-                            debug!("data sent successfully");
-                            break 'sendLine;
-                        }
-                        Err(e) => {
-                            error!("Failed to send metrics: {}", e);
-                            spin_sleep::sleep(Duration::from_micros(1000000));
-                            let reconnect_res = graphite_state.reconnect();
-                            if let Some(err) = reconnect_res.err() {
-                                info!("Failed to reconnect server: {}", err);
-                            }
-                        }
-                    }
-                },
-                Err(e) => {
-                    if e != TryRecvError::Empty {
-                        error!("Error when receiving data: {:?}", e);
-                    } else {
-                        spin_sleep::sleep(Duration::from_micros(500000));
-                    }
-                }
-            }
+    Ok(())
+}
+
+/// Read the sensors and send the readings to the outputs until stopped.
+fn daemon(config: Config, config_file: Option<PathBuf>) -> std::io::Result<()> {
+    config.check_outputs()?;
+
+    if config.log_to_journal {
+        if let Err(e) = logging::use_journal() {
+            warn!("Cannot log to journald, logging to stderr: {}", e);
         }
-    });
+    }
+
+    let sinks = start_sinks(&config)?;
+
+    // Feed a recording through BSEC instead of reading the sensor
+
+    if let Some(replay_file) = config.replay_file.as_ref() {
+        replay(replay_file, &sinks, &config)?;
+        sinks.close();
+        return Ok(());
+    }
 
     // Connect to Sensor and setup Internal States
 
-    let mut bme: Option<Device<I2cDriver>> = None;
-
-    for i in 0..9 {
-        let pathstring = format!("/dev/i2c-{}", i);
-        let path = Path::new(&pathstring);
-        match path.try_exists() {
-            Ok(result) => {
-                if result {
-                    info!("Found i2c Device on {}", path.display());
-                    let driver = bme::create_device(path);
-                    bme = bme::init(driver);
-                    break;
-                }
-            }
-            Err(_) => {}
-        }
+    if config.mock {
+        info!("Using simulated BME688 on mock i2c bus");
+        let bme = bme::init(MockI2c::default().with_warmup(3))
+            .ok_or_else(|| Error::new(ErrorKind::Other, "Cannot initialize mock Device."))?;
+        let sensor = Sensor::new(
+            sensor::mock_id(),
+            config.state_file.clone(),
+            bme,
+            config.record_file.clone(),
+            &config,
+        )?;
+        return run(vec![sensor], sinks, config, config_file);
+    }
+
+    let found = sensor::find_sensors(&config);
+
+    if found.is_empty() {
+        return Err(Error::new(ErrorKind::NotFound, "Cannot find i2c Device."));
     }
 
-    let mut bme = bme.expect("Cannot find i2c Device.");
+    let single = found.len() == 1;
 
-    let mut bsec_state = bsec::State::default();
+    let mut sensors = Vec::with_capacity(found.len());
 
-    bsec::get_version(&mut bsec_state);
+    for found in found {
+        let record_file = match config.record_file.as_ref() {
+            Some(record_file) if !single => {
+                Some(sensor::file_for_sensor(record_file, &found.id.name))
+            }
+            record_file => record_file.cloned(),
+        };
+
+        sensors.push(Sensor::new(
+            found.id,
+            found.state_file,
+            found.device,
+            record_file,
+            &config,
+        )?);
+    }
 
-    bsec::init(&mut bsec_state);
+    run(sensors, sinks, config, config_file)
+}
 
-    // Load BSEC last state
+/// Start a thread for every configured output.
+fn start_sinks(config: &Config) -> std::io::Result<Sinks> {
+    let mut sinks = Sinks::default();
+
+    let mut graphite_stats = None;
+
+    if let Some(graphite_config) = config.graphite.as_ref() {
+        let client = graphite::Client::new(
+            graphite_config,
+            config.gas_labels.clone(),
+            config.min_accuracy,
+        )?;
+        graphite_stats = Some(client.stats());
+        sinks.add(sink::spawn(
+            "graphite",
+            Box::new(client),
+            config.sink_options("graphite"),
+        ));
+    }
 
-    let last_state = fs::read("last_state.bin").ok();
+    // Serve the latest readings for Prometheus to scrape
 
-    match last_state {
-        Some(serialized_state) => {
-            bsec::set_bsec_state(serialized_state);
-        }
-        None => {
-            info!("Last BSEC state not found.");
+    if let Some(address) = config.prometheus_listen.as_ref() {
+        let mut exporter = Exporter::new(config.min_accuracy, config.gas_labels.clone());
+        if let Some(stats) = graphite_stats {
+            exporter = exporter.with_graphite_stats(stats);
         }
+        exporter.serve(address)?;
+        sinks.add(sink::spawn(
+            "prometheus",
+            Box::new(exporter),
+            config.sink_options("prometheus"),
+        ));
     }
 
-    // Handle Graceful Exit
+    // Publish readings to an MQTT broker for Home Assistant
+
+    if let Some(mqtt_config) = config.mqtt.clone() {
+        let kinds = config
+            .outputs
+            .iter()
+            .filter_map(|output| OutputKind::from_sensor_id(output.sensor_id))
+            .collect();
+        let publisher = mqtt::Publisher::new(mqtt_config, kinds, config.min_accuracy);
+        sinks.add(sink::spawn(
+            "mqtt",
+            Box::new(publisher),
+            config.sink_options("mqtt"),
+        ));
+    }
 
-    let (exit_tx, exit_rx) = channel();
+    if let Some(influx_config) = config.influx.clone() {
+        let writer = influx::Writer::new(
+            influx_config,
+            config.gas_labels.clone(),
+            config.min_accuracy,
+        )?;
+        sinks.add(sink::spawn(
+            "influx",
+            Box::new(writer),
+            config.sink_options("influx"),
+        ));
+    }
 
-    ctrlc::set_handler(move || {
-        let serialized_state = bsec::get_bsec_state();
+    if let Some(output_file) = config.output_file.as_ref() {
+        let appender = file::Appender::open(output_file)?;
+        sinks.add(sink::spawn(
+            "file",
+            Box::new(appender),
+            config.sink_options("file"),
+        ));
+    }
 
-        fs::write("last_state.bin", serialized_state)
-            .map(|_| info!("BSEC state saved."))
-            .unwrap_or_else(|_| warn!("Error saving BSEC state."));
+    Ok(sinks)
+}
 
-        exit_tx
-            .send(0)
-            .unwrap_or_else(|_| error!("Failed to signal termination."));
-    })
-    .expect("Error setting Ctrl-C handler");
+/// Measure every sensor when BSEC asks for it, sleeping until the earliest
+/// next call in between.
+fn run<I: Reopen>(
+    mut sensors: Vec<Sensor<I>>,
+    mut sinks: Sinks,
+    mut config: Config,
+    config_file: Option<PathBuf>,
+) -> std::io::Result<()> {
+    let mut run_loop = true;
+    let mut failure = None;
 
-    // Setup sensor config
+    // Stop on SIGINT and SIGTERM, reload the config on SIGHUP and trigger a
+    // measurement on demand in ULP mode with SIGUSR1. The signal thread only
+    // passes events on, BSEC is called from this thread alone.
 
-    bsec::update_subscription(&mut bsec_state, BSEC_SAMPLE_RATE_LP as f32);
+    let (event_tx, event_rx) = channel();
 
-    // Start Data reading loop
+    let _control_socket = match config.control_socket.as_ref() {
+        Some(path) => Some(ControlSocket::listen(path, event_tx.clone())?),
+        None => None,
+    };
 
-    while run_loop {
-        let start_timestamp = Local::now().naive_utc().timestamp_nanos();
-
-        info!("Calling at:     {}", Local::now());
-
-        bsec::get_sensor_config(&mut bsec_state, start_timestamp);
-
-        bme.set_config(
-            DeviceConfig::default()
-                .filter(Filter::Size3)
-                .odr(Odr::StandbyNone)
-                .oversample_humidity(bsec_state.sensor_settings.humidity_oversampling.into())
-                .oversample_temperature(bsec_state.sensor_settings.temperature_oversampling.into())
-                .oversample_pressure(bsec_state.sensor_settings.pressure_oversampling.into()),
-        )
-        .expect("failed setting config");
-
-        let heater_config = GasHeaterConfig::default()
-            .enable()
-            .heater_temp(bsec_state.sensor_settings.heater_temperature)
-            .heater_duration(bsec_state.sensor_settings.heater_duration)
-            .heater_temp_profile(
-                bsec_state
-                    .sensor_settings
-                    .heater_duration_profile
-                    .as_mut_ptr(),
-            )
-            .heater_dur_profile(
-                bsec_state
-                    .sensor_settings
-                    .heater_duration_profile
-                    .as_mut_ptr(),
-            );
-
-        bme.set_gas_heater_conf(bsec_state.sensor_settings.op_mode.into(), heater_config)
-            .expect("failed setting heater config");
-
-        // -------------------------------------------------------
-
-        if bsec_state.sensor_settings.trigger_measurement == 1 {
-            bme.set_op_mode(bsec_state.sensor_settings.op_mode.into())
-                .expect("Failed setting operation mode");
-
-            let delay_period = bme.get_measure_duration(bsec_state.sensor_settings.op_mode.into());
-            bme.interface.delay(delay_period);
-
-            let mut measure_results = bme.get_data(bsec_state.sensor_settings.op_mode.into());
-
-            // Read data from sensor until valid measurement is obtained
-
-            for i in 1..100 {
-                if measure_results.is_ok() && // no new data
-                 measure_results.as_ref().unwrap()[0].status & 0b10000 == 0b10000 && // heater stable
-                    measure_results.as_ref().unwrap()[0].status & 0b100000 == 0b100000
-                // gas measurement valid
-                {
-                    break;
-                }
+    let mut signals = Signals::new(&[SIGINT, SIGTERM, SIGHUP, SIGUSR1])?;
 
-                if i == 50 {
-                    info!("Sensor not ready, polling again...");
+    thread::spawn(move || {
+        let mut stopping = false;
+
+        for signal in signals.forever() {
+            let event = match signal {
+                SIGUSR1 => Event::MeasureOnDemand,
+                SIGHUP => Event::Reload(None),
+                _ if stopping => {
+                    error!("Stopping immediately, BSEC state not saved.");
+                    process::exit(1);
                 }
+                _ => {
+                    stopping = true;
+                    info!(
+                        "Received {}, stopping.",
+                        low_level::signal_name(signal).unwrap_or("signal")
+                    );
+
+                    // Stop even if a measurement or an output hangs
+                    thread::spawn(|| {
+                        thread::sleep(SHUTDOWN_TIMEOUT);
+                        error!(
+                            "Not stopped after {} s, exiting.",
+                            SHUTDOWN_TIMEOUT.as_secs()
+                        );
+                        process::exit(1);
+                    });
+
+                    Event::Exit
+                }
+            };
 
-                bme.interface.delay(10000);
-
-                measure_results = bme.get_data(bsec_state.sensor_settings.op_mode.into());
+            if event_tx.send(event).is_err() {
+                break;
             }
+        }
+    });
 
-            if measure_results.is_ok() {
-                let measure_results = measure_results.unwrap();
-
-                debug!("{:#?}", measure_results[0]);
-
-                let sensor_inputs =
-                    bsec::process_data(&bsec_state, &measure_results[0], start_timestamp);
+    // Tell systemd once the sensors are set up, when started as a notify
+    // service
 
-                debug!("{:?}", sensor_inputs);
+    let mut notifier = systemd::Notifier::from_env();
 
-                let sensor_outputs = bsec::do_steps(&mut bsec_state, &sensor_inputs);
+    if let Some(notifier) = notifier.as_mut() {
+        notifier.ready();
+    }
 
-                let metrics_string = graphite::build_output(sensor_outputs, start_timestamp);
+    // Start Data reading loop
 
-                if let Err(e) = data_tx.send(metrics_string) {
-                    warn!("Failed to send sensor output to output thread: {:?}", e);
+    while run_loop {
+        let now = Local::now().naive_utc().timestamp_nanos();
+
+        // Give up on a sensor that cannot be recovered, saving its state, and
+        // keep measuring the others
+        let mut i = 0;
+        while i < sensors.len() {
+            if sensors[i].next_call <= now {
+                if let Err(e) = sensors[i].measure(&sinks) {
+                    let mut sensor = sensors.remove(i);
+                    sensor.shutdown();
+                    sinks.remove(&sensor.id);
+                    if sensors.is_empty() {
+                        failure = Some(e);
+                    } else {
+                        error!("{}, giving up on it.", e);
+                    }
+                    continue;
                 }
             }
+            i += 1;
+        }
+
+        if let Some(e) = failure.as_ref() {
+            error!("{}, stopping.", e);
+            if let Some(notifier) = notifier.as_mut() {
+                notifier.stopping();
+            }
+            break;
+        }
+
+        // A hung measurement stops the pings, and systemd restarts the program
+        if let Some(notifier) = notifier.as_mut() {
+            notifier.ping();
+            notifier.status(&status(&sensors));
         }
 
         // ---------------------------------------------
 
-        let next_call = bsec_state.sensor_settings.next_call;
-
-        info!(
-            "Next call time: {}",
-            NaiveDateTime::from_timestamp_opt(
-                next_call / 1000 / 1000 / 1000,
-                (next_call % 1000000000) as u32
-            )
-            .unwrap()
-            .and_local_timezone(Utc)
-            .unwrap()
-            .with_timezone(&Local::now().timezone())
-        );
+        let next_call = sensors
+            .iter()
+            .map(|sensor| sensor.next_call)
+            .min()
+            .unwrap_or(now);
 
-        let wait_time = max(
+        let mut wait_time = max(
             1000,
             (next_call - Local::now().naive_utc().timestamp_nanos()) / 1000 - 200,
         );
+        if let Some(interval) = notifier.as_ref().and_then(|n| n.watchdog_interval()) {
+            wait_time = wait_time.min(interval.as_micros() as i64);
+        }
         info!("Sleeping for: {} ms", wait_time / 1000);
 
-        spin_sleep::sleep(Duration::from_micros(wait_time as u64));
-
-        exit_rx
-            .try_recv()
-            .and_then(|_| {
+        match wait_for_event(&event_rx, Duration::from_micros(wait_time as u64)) {
+            Some(Event::Exit) => {
+                if let Some(notifier) = notifier.as_mut() {
+                    notifier.stopping();
+                }
                 run_loop = false;
-                Ok(())
-            })
-            .ok();
+            }
+            Some(Event::MeasureOnDemand) => {
+                info!("Measurement on demand requested.");
+                for sensor in sensors.iter_mut() {
+                    if sensor.bsec.request_measurement_on_demand().is_ok() {
+                        // Ask BSEC for the measurement now, not at the next ULP call
+                        sensor.next_call = 0;
+                    }
+                }
+            }
+            Some(Event::Reload(reply_tx)) => {
+                info!("Reloading config.");
+                if let Some(notifier) = notifier.as_mut() {
+                    notifier.reloading();
+                }
+                let result = reload(
+                    &mut sensors,
+                    &mut sinks,
+                    &mut config,
+                    config_file.as_deref(),
+                );
+                match result.as_ref() {
+                    Ok(()) => info!("Config reloaded."),
+                    Err(e) => error!("Reload failed: {}", e),
+                }
+                if let Some(notifier) = notifier.as_mut() {
+                    notifier.ready();
+                }
+                if let Some(reply_tx) = reply_tx {
+                    let _ = reply_tx.send(result);
+                }
+            }
+            None => {}
+        }
+    }
+
+    for sensor in sensors.iter_mut() {
+        sensor.shutdown();
+    }
+
+    sinks.close_within(SINK_CLOSE_TIMEOUT);
+
+    if let Some(e) = failure {
+        return Err(e);
+    }
+
+    info!("Stopped.");
+
+    Ok(())
+}
+
+/// e.g. `study: IAQ 52 (accuracy 3), bedroom: IAQ 110 (accuracy 1)`
+fn status<I: Interface>(sensors: &[Sensor<I>]) -> String {
+    sensors
+        .iter()
+        .map(|sensor| match sensor.iaq {
+            Some((iaq, accuracy)) => {
+                format!("{}: IAQ {:.0} (accuracy {})", sensor.id.name, iaq, accuracy)
+            }
+            None => format!("{}: no IAQ yet", sensor.id.name),
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Read the config file again and apply what can change while running: the
+/// outputs, metric names and the BSEC subscription. The sensors keep their
+/// BSEC state and device.
+fn reload<I: Reopen>(
+    sensors: &mut [Sensor<I>],
+    sinks: &mut Sinks,
+    config: &mut Config,
+    config_file: Option<&Path>,
+) -> Result<(), String> {
+    let new_config = Config::load(config_file).map_err(|e| e.to_string())?;
+    new_config.check_outputs().map_err(|e| e.to_string())?;
+
+    for (setting, changed) in [
+        ("SENSORS", new_config.sensors != config.sensors),
+        ("BME_MOCK", new_config.mock != config.mock),
+        ("STATE_FILE", new_config.state_file != config.state_file),
+        ("RECORD_FILE", new_config.record_file != config.record_file),
+        (
+            "BSEC_CONFIG_FILE",
+            new_config.bsec_config_file != config.bsec_config_file,
+        ),
+        (
+            "CONTROL_SOCKET",
+            new_config.control_socket != config.control_socket,
+        ),
+        (
+            "LOG_TARGET",
+            new_config.log_to_journal != config.log_to_journal,
+        ),
+    ] {
+        if changed {
+            warn!("A changed {} takes effect after a restart.", setting);
+        }
+    }
+
+    let subscription = |config: &Config| {
+        let outputs: Vec<(u8, f32)> = config
+            .outputs
+            .iter()
+            .map(|output| (output.sensor_id, output.sample_rate))
+            .collect();
+        (config.sample_rate, outputs)
+    };
+    let resubscribe = subscription(&new_config) != subscription(config);
+
+    // Put back the settings of the sensors reloaded so far
+    let roll_back = |sensors: &mut [Sensor<I>]| {
+        for sensor in sensors.iter_mut() {
+            if let Err(e) = sensor.reload(config, resubscribe) {
+                warn!("Cannot restore the settings of {}: {}", sensor.id.name, e);
+            }
+        }
+    };
+
+    for i in 0..sensors.len() {
+        if let Err(e) = sensors[i].reload(&new_config, resubscribe) {
+            let message = format!("{}: {}", sensors[i].id.name, e);
+            roll_back(&mut sensors[..i]);
+            return Err(message);
+        }
+    }
+
+    // The old outputs have to stop first, e.g. to free the Prometheus port
+    std::mem::take(sinks).close_within(SINK_CLOSE_TIMEOUT);
+
+    *sinks = match start_sinks(&new_config) {
+        Ok(new_sinks) => new_sinks,
+        Err(e) => {
+            roll_back(sensors);
+            *sinks = start_sinks(config).map_err(|old_e| {
+                format!(
+                    "cannot start outputs: {}, nor the previous ones: {}",
+                    e, old_e
+                )
+            })?;
+            return Err(format!(
+                "cannot start outputs, kept the previous ones: {}",
+                e
+            ));
+        }
+    };
+
+    *config = new_config;
+
+    Ok(())
+}
+
+/// Wait until `timeout` has passed or an event arrives. The last few
+/// milliseconds are spun so BSEC is called right at its next call time.
+fn wait_for_event(event_rx: &Receiver<Event>, timeout: Duration) -> Option<Event> {
+    let deadline = Instant::now() + timeout;
+
+    if let Ok(event) = event_rx.recv_timeout(timeout.saturating_sub(Duration::from_millis(5))) {
+        return Some(event);
+    }
+
+    spin_sleep::sleep(deadline.saturating_duration_since(Instant::now()));
+
+    event_rx.try_recv().ok()
+}
+
+fn replay(path: &Path, sinks: &Sinks, config: &Config) -> std::io::Result<()> {
+    let readings = recording::read_recording(path)?;
+
+    let id = SensorId {
+        name: String::from(sensor::DEFAULT_NAME),
+        bus: String::from("replay"),
+        address: 0,
+        location: None,
+    };
+
+    let mut bsec_state = sensor::init_bsec(config)?;
+
+    bsec_state.update_subscription(config.sample_rate, &config.outputs)?;
+
+    let total = readings.len();
+    let mut failed = 0;
+
+    for reading in readings {
+        match bsec_state.get_sensor_config(reading.timestamp) {
+            Err(e) if !e.is_warning() => {
+                warn!("Sensor control at {} failed: {}", reading.timestamp, e);
+                failed += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        let sensor_inputs = bsec_state.process_data(&reading);
+
+        match bsec_state.do_steps(&sensor_inputs) {
+            // Wait for room in the queues rather than drop readings
+            Ok(sensor_outputs) => {
+                sinks.send_wait(Reading::new(&id, reading.timestamp, &sensor_outputs))
+            }
+            Err(e) => {
+                warn!("Do steps at {} failed: {}", reading.timestamp, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        warn!(
+            "Replay of {} finished, BSEC failed on {} of {} readings.",
+            path.display(),
+            failed,
+            total
+        );
+    } else {
+        info!("Replay of {} finished.", path.display());
     }
 
     Ok(())
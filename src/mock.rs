@@ -0,0 +1,369 @@
+use crate::bme::Reopen;
+use bme68x_rust::{CommInterface, Error as BmeError, Interface};
+use log::debug;
+
+const REG_COEFF3: usize = 0x00;
+const REG_FIELD0: usize = 0x1d;
+const REG_CTRL_GAS_1: usize = 0x71;
+const REG_CTRL_MEAS: usize = 0x74;
+const REG_COEFF1: usize = 0x8a;
+const REG_CHIP_ID: usize = 0xd0;
+const REG_SOFT_RESET: usize = 0xe0;
+const REG_COEFF2: usize = 0xe1;
+const REG_VARIANT_ID: usize = 0xf0;
+
+const CHIP_ID: u8 = 0x61;
+const VARIANT_GAS_HIGH: u8 = 0x01;
+const SOFT_RESET_CMD: u8 = 0xb6;
+
+const LEN_FIELD: usize = 17;
+const NEW_DATA: u8 = 0b1000_0000;
+const GAS_VALID: u8 = 0b10_0000;
+const HEAT_STAB: u8 = 0b1_0000;
+const MODE_MASK: u8 = 0b11;
+const MODE_FORCED: u8 = 0b01;
+const MODE_PARALLEL: u8 = 0b10;
+
+/// Calibration coefficients chosen so that the float compensation in
+/// `bme68x_rust` reduces to simple linear functions of the ADC values:
+///
+/// - `par_t2 = 5120`, everything else zero: `temperature = adc_temp / 16384`
+/// - `par_h2 = 512`, everything else zero: `humidity = adc_hum / 512`
+/// - `par_p1 = 6250`, everything else zero: `pressure = 1048576 - adc_pres`
+const CALIB_COEFF1: [u8; 23] = [
+    0x00, 0x14, 0x00, 0x00, 0x6a, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+const CALIB_COEFF2: [u8; 14] = [
+    0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+const CALIB_COEFF3: [u8; 5] = [0x00, 0x00, 0x00, 0x00, 0x00];
+
+/// Physical values reported by the simulated sensor.
+#[derive(Debug, Clone, Copy)]
+pub struct Reading {
+    /// Degrees Celsius, 0 to 63.
+    pub temperature: f32,
+    /// Percent relative humidity, 0 to 100.
+    pub humidity: f32,
+    /// Pascal.
+    pub pressure: f32,
+    /// Ohm.
+    pub gas_resistance: f32,
+}
+
+impl Default for Reading {
+    fn default() -> Self {
+        Reading {
+            temperature: 22.5,
+            humidity: 45.0,
+            pressure: 101325.0,
+            gas_resistance: 50000.0,
+        }
+    }
+}
+
+/// Simulated BME688 behind the same `Interface` as `bme::I2cDriver`.
+///
+/// Registers are kept in a flat 256 byte map. Writing forced or parallel mode
+/// into `ctrl_meas` latches the current `Reading` into the field registers,
+/// and a forced measurement drops the sensor back into sleep mode like the
+/// real chip does.
+pub struct MockI2c {
+    regs: [u8; 256],
+    reading: Reading,
+    meas_index: u8,
+    /// Number of field reads left before the heater reports stable.
+    warmup_reads: u32,
+    warmup: u32,
+}
+
+impl Default for MockI2c {
+    fn default() -> Self {
+        MockI2c::new(Reading::default())
+    }
+}
+
+impl MockI2c {
+    pub fn new(reading: Reading) -> Self {
+        let mut mock = MockI2c {
+            regs: [0; 256],
+            reading,
+            meas_index: 0,
+            warmup_reads: 0,
+            warmup: 0,
+        };
+        mock.reset();
+        mock
+    }
+
+    /// Report an unstable heater for the first `reads` field reads after
+    /// every trigger, to exercise the polling loop.
+    pub fn with_warmup(mut self, reads: u32) -> Self {
+        self.warmup = reads;
+        self
+    }
+
+    fn reset(&mut self) {
+        self.regs = [0; 256];
+        self.regs[REG_CHIP_ID] = CHIP_ID;
+        self.regs[REG_VARIANT_ID] = VARIANT_GAS_HIGH;
+        self.regs[REG_COEFF1..REG_COEFF1 + CALIB_COEFF1.len()].copy_from_slice(&CALIB_COEFF1);
+        self.regs[REG_COEFF2..REG_COEFF2 + CALIB_COEFF2.len()].copy_from_slice(&CALIB_COEFF2);
+        self.regs[REG_COEFF3..REG_COEFF3 + CALIB_COEFF3.len()].copy_from_slice(&CALIB_COEFF3);
+    }
+
+    fn trigger(&mut self, mode: u8) {
+        let fields = if mode == MODE_PARALLEL { 3 } else { 1 };
+        let profile_len = (self.regs[REG_CTRL_GAS_1] & 0x0f) as usize + 1;
+        let run_gas = self.regs[REG_CTRL_GAS_1] & 0b11_0000 != 0;
+
+        for field in 0..fields {
+            let gas_index = if mode == MODE_PARALLEL {
+                (self.meas_index as usize + field) % profile_len
+            } else {
+                self.regs[REG_CTRL_GAS_1] as usize & 0x0f
+            };
+            let data = self.encode_field(gas_index as u8, run_gas);
+            let start = REG_FIELD0 + field * LEN_FIELD;
+            self.regs[start..start + LEN_FIELD].copy_from_slice(&data);
+            self.meas_index = self.meas_index.wrapping_add(1);
+        }
+
+        self.warmup_reads = self.warmup;
+
+        if mode == MODE_FORCED {
+            self.regs[REG_CTRL_MEAS] &= !MODE_MASK;
+        }
+
+        debug!("Mock BME688 triggered {} field(s)", fields);
+    }
+
+    fn encode_field(&self, gas_index: u8, run_gas: bool) -> [u8; LEN_FIELD] {
+        let mut data = [0u8; LEN_FIELD];
+
+        let adc_pres = (1048576.0 - self.reading.pressure).clamp(0.0, 1048575.0) as u32;
+        let adc_temp = (self.reading.temperature * 16384.0).clamp(0.0, 1048575.0) as u32;
+        let adc_hum = (self.reading.humidity * 512.0).clamp(0.0, 65535.0) as u32;
+        let (adc_gas, gas_range) = encode_gas(self.reading.gas_resistance);
+
+        data[0] = NEW_DATA | (gas_index & 0x0f);
+        data[1] = self.meas_index;
+        data[2] = (adc_pres >> 12) as u8;
+        data[3] = (adc_pres >> 4) as u8;
+        data[4] = ((adc_pres & 0x0f) << 4) as u8;
+        data[5] = (adc_temp >> 12) as u8;
+        data[6] = (adc_temp >> 4) as u8;
+        data[7] = ((adc_temp & 0x0f) << 4) as u8;
+        data[8] = (adc_hum >> 8) as u8;
+        data[9] = adc_hum as u8;
+        data[15] = (adc_gas >> 2) as u8;
+        data[16] = ((adc_gas & 0b11) << 6) as u8 | gas_range;
+        if run_gas {
+            data[16] |= GAS_VALID | HEAT_STAB;
+        }
+
+        data
+    }
+}
+
+/// Invert the BME688 high-variant gas resistance formula
+/// `R = 1e6 * (262144 >> range) / (4096 + 3 * (adc - 512))`.
+fn encode_gas(resistance: f32) -> (u32, u8) {
+    let resistance = resistance.max(1.0) as f64;
+    for range in 0..16u8 {
+        let divisor = 1e6 * (262144u32 >> range) as f64 / resistance;
+        let adc = (divisor - 4096.0) / 3.0 + 512.0;
+        if (0.0..=1023.0).contains(&adc) {
+            return (adc.round() as u32, range);
+        }
+    }
+    (1023, 15)
+}
+
+impl Interface for MockI2c {
+    fn interface_type(&self) -> CommInterface {
+        CommInterface::I2C
+    }
+
+    fn delay(&self, _period: u32) {}
+
+    fn read(&mut self, reg_addr: u8, reg_data: &mut [u8]) -> Result<(), BmeError> {
+        let start = reg_addr as usize;
+        let end = start + reg_data.len();
+        if end > self.regs.len() {
+            return Err(BmeError::CommunicationFailure);
+        }
+        reg_data.copy_from_slice(&self.regs[start..end]);
+
+        // Hide the heater stable bit while warming up
+        if (REG_FIELD0..REG_FIELD0 + 3 * LEN_FIELD).contains(&start) && self.warmup_reads > 0 {
+            self.warmup_reads -= 1;
+            for field in 0..3 {
+                let status = REG_FIELD0 + field * LEN_FIELD + 16;
+                if (start..end).contains(&status) {
+                    reg_data[status - start] &= !(GAS_VALID | HEAT_STAB);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, reg_addr: u8, reg_data: &[u8]) -> Result<(), BmeError> {
+        for (i, b) in reg_data.iter().enumerate() {
+            let reg = reg_addr as usize + i;
+            if reg >= self.regs.len() {
+                return Err(BmeError::CommunicationFailure);
+            }
+
+            match reg {
+                REG_SOFT_RESET => {
+                    if *b == SOFT_RESET_CMD {
+                        debug!("Mock BME688 soft reset");
+                        self.reset();
+                    }
+                }
+                REG_CHIP_ID | REG_VARIANT_ID => {}
+                REG_CTRL_MEAS => {
+                    self.regs[reg] = *b;
+                    match b & MODE_MASK {
+                        MODE_FORCED | MODE_PARALLEL => self.trigger(b & MODE_MASK),
+                        _ => {}
+                    }
+                }
+                _ => self.regs[reg] = *b,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Reopen for MockI2c {
+    fn reopen(&self) -> Result<Self, BmeError> {
+        Ok(MockI2c::new(self.reading).with_warmup(self.warmup))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bme;
+    use bme68x_rust::{
+        Device, DeviceConfig, Filter, GasHeaterConfig, Odr, OperationMode, Sample, SensorData,
+    };
+
+    const READING: Reading = Reading {
+        temperature: 25.0,
+        humidity: 50.0,
+        pressure: 100000.0,
+        gas_resistance: 80000.0,
+    };
+
+    fn configured(mock: MockI2c, mode: OperationMode, heater: GasHeaterConfig) -> Device<MockI2c> {
+        let mut device = bme::init(mock).expect("mock initializes");
+        device
+            .set_config(
+                DeviceConfig::default()
+                    .filter(Filter::Size3)
+                    .odr(Odr::StandbyNone)
+                    .oversample_humidity(Sample::X1)
+                    .oversample_temperature(Sample::X1)
+                    .oversample_pressure(Sample::X1),
+            )
+            .unwrap();
+        device.set_gas_heater_conf(mode, heater).unwrap();
+        device.set_op_mode(mode).unwrap();
+        device
+    }
+
+    fn assert_reading(data: &SensorData) {
+        assert!((data.temperature - READING.temperature).abs() < 0.01);
+        assert!((data.humidity - READING.humidity).abs() < 0.01);
+        assert!((data.pressure - READING.pressure).abs() < 0.1);
+        // The gas ADC has 10 bits per range
+        assert!((data.gas_resistance / READING.gas_resistance - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn forced_measurement() {
+        let heater = GasHeaterConfig::default()
+            .enable()
+            .heater_temp(300)
+            .heater_duration(100);
+        let mut device = configured(MockI2c::new(READING), OperationMode::Forced, heater);
+
+        let data = device.get_data(OperationMode::Forced).unwrap();
+
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0].status & NEW_DATA, NEW_DATA);
+        assert_eq!(data[0].status & GAS_VALID, GAS_VALID);
+        assert_eq!(data[0].status & HEAT_STAB, HEAT_STAB);
+        assert_reading(&data[0]);
+    }
+
+    #[test]
+    fn heater_stable_after_warmup() {
+        let heater = GasHeaterConfig::default()
+            .enable()
+            .heater_temp(300)
+            .heater_duration(100);
+        let mock = MockI2c::new(READING).with_warmup(2);
+        let mut device = configured(mock, OperationMode::Forced, heater);
+
+        for _ in 0..2 {
+            let data = device.get_data(OperationMode::Forced).unwrap();
+            assert_eq!(data[0].status & NEW_DATA, NEW_DATA);
+            assert_eq!(data[0].status & (GAS_VALID | HEAT_STAB), 0);
+        }
+
+        let data = device.get_data(OperationMode::Forced).unwrap();
+        assert_eq!(
+            data[0].status & (GAS_VALID | HEAT_STAB),
+            GAS_VALID | HEAT_STAB
+        );
+        assert_reading(&data[0]);
+
+        // Every trigger starts warming up again
+        device.set_op_mode(OperationMode::Forced).unwrap();
+        let data = device.get_data(OperationMode::Forced).unwrap();
+        assert_eq!(data[0].status & HEAT_STAB, 0);
+    }
+
+    #[test]
+    fn parallel_measurement() {
+        let mut temperatures = [320u16, 100, 200];
+        let mut durations = [5u16, 2, 10];
+        let heater = GasHeaterConfig::default()
+            .enable()
+            .heater_temp_profile(temperatures.as_mut_ptr())
+            .heater_dur_profile(durations.as_mut_ptr())
+            .profile_len(3)
+            .shared_heater_duration(100);
+        let mut device = configured(MockI2c::new(READING), OperationMode::Parallel, heater);
+
+        let data = device.get_data(OperationMode::Parallel).unwrap();
+
+        assert_eq!(data.len(), 3);
+        for field in &data {
+            assert_eq!(field.status & NEW_DATA, NEW_DATA);
+            assert_eq!(
+                field.status & (GAS_VALID | HEAT_STAB),
+                GAS_VALID | HEAT_STAB
+            );
+            assert!(field.gas_index < 3);
+            assert_reading(field);
+        }
+    }
+
+    #[test]
+    fn soft_reset_keeps_chip_id() {
+        let mut mock = MockI2c::default();
+        mock.write(REG_SOFT_RESET as u8, &[SOFT_RESET_CMD]).unwrap();
+
+        assert_eq!(
+            bme::read_id(&mut mock).unwrap(),
+            (CHIP_ID, VARIANT_GAS_HIGH)
+        );
+    }
+}
@@ -0,0 +1,608 @@
+use crate::config::MqttConfig;
+use crate::file::escape;
+use crate::sink::{OutputKind, Reading, Sink};
+use log::{debug, info};
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+const KEEP_ALIVE_SECS: u16 = 60;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// A Home Assistant sensor entity and the BSEC outputs that feed it.
+struct Entity {
+    key: &'static str,
+    name: &'static str,
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+    kinds: &'static [OutputKind],
+}
+
+const IAQ_KINDS: &[OutputKind] = &[OutputKind::Iaq, OutputKind::StaticIaq];
+
+const ENTITIES: [Entity; 8] = [
+    Entity {
+        key: "temperature",
+        name: "Temperature",
+        device_class: Some("temperature"),
+        unit: Some("°C"),
+        kinds: &[OutputKind::HeatCompensatedTemperature],
+    },
+    Entity {
+        key: "humidity",
+        name: "Humidity",
+        device_class: Some("humidity"),
+        unit: Some("%"),
+        kinds: &[OutputKind::HeatCompensatedHumidity],
+    },
+    Entity {
+        key: "pressure",
+        name: "Pressure",
+        device_class: Some("pressure"),
+        unit: Some("hPa"),
+        kinds: &[OutputKind::RawPressure],
+    },
+    Entity {
+        key: "iaq",
+        name: "IAQ",
+        device_class: Some("aqi"),
+        unit: None,
+        kinds: IAQ_KINDS,
+    },
+    Entity {
+        key: "co2",
+        name: "CO2 equivalent",
+        device_class: Some("carbon_dioxide"),
+        unit: Some("ppm"),
+        kinds: &[OutputKind::Co2Equivalent],
+    },
+    Entity {
+        key: "voc",
+        name: "Breath VOC equivalent",
+        device_class: Some("volatile_organic_compounds_parts"),
+        unit: Some("ppm"),
+        kinds: &[OutputKind::BreathVocEquivalent],
+    },
+    Entity {
+        key: "gas_resistance",
+        name: "Gas resistance",
+        device_class: None,
+        unit: Some("Ω"),
+        kinds: &[OutputKind::RawGas],
+    },
+    Entity {
+        key: "iaq_accuracy",
+        name: "IAQ accuracy",
+        device_class: None,
+        unit: None,
+        kinds: IAQ_KINDS,
+    },
+];
+
+/// Key of an output in the JSON state message.
+fn state_key(kind: OutputKind) -> Option<&'static str> {
+    ENTITIES
+        .iter()
+        .filter(|entity| !entity.key.ends_with("_accuracy"))
+        .find(|entity| entity.kinds.contains(&kind))
+        .map(|entity| entity.key)
+}
+
+/// Publishes every reading as a JSON state message, and announces the
+/// sensors to Home Assistant with retained discovery configs.
+pub struct Publisher {
+    config: MqttConfig,
+    /// Outputs subscribed from BSEC, only their entities are announced.
+    kinds: Vec<OutputKind>,
+    min_accuracy: u8,
+    connection: Option<TcpStream>,
+    /// Sensors announced on the current connection.
+    announced: HashSet<String>,
+    last_packet: Instant,
+    next_connect: Instant,
+}
+
+impl Publisher {
+    pub fn new(config: MqttConfig, kinds: Vec<OutputKind>, min_accuracy: u8) -> Publisher {
+        Publisher {
+            config,
+            kinds,
+            min_accuracy,
+            connection: None,
+            announced: HashSet::new(),
+            last_packet: Instant::now(),
+            next_connect: Instant::now(),
+        }
+    }
+
+    fn availability_topic(&self) -> String {
+        format!("{}/status", self.config.topic_prefix)
+    }
+
+    fn state_topic(&self, sensor: &str) -> String {
+        format!("{}/{}/state", self.config.topic_prefix, topic_id(sensor))
+    }
+
+    pub fn connect(&mut self) -> Result<(), Error> {
+        debug!("Connecting to MQTT broker {}", self.config.url);
+
+        let mut connection = connect(&self.config.url)?;
+        connection.set_nodelay(true)?;
+        connection.set_read_timeout(Some(IO_TIMEOUT))?;
+        connection.set_write_timeout(Some(IO_TIMEOUT))?;
+
+        let will_topic = self.availability_topic();
+
+        // Clean session, with a retained "offline" will
+        let mut flags = 0b0010_0110;
+        if self.config.username.is_some() {
+            flags |= 0b1000_0000;
+        }
+        if self.config.password.is_some() {
+            flags |= 0b0100_0000;
+        }
+
+        let mut body = Vec::new();
+        push_string(&mut body, b"MQTT");
+        body.push(4);
+        body.push(flags);
+        body.extend_from_slice(&KEEP_ALIVE_SECS.to_be_bytes());
+        push_string(&mut body, self.config.client_id.as_bytes());
+        push_string(&mut body, will_topic.as_bytes());
+        push_string(&mut body, b"offline");
+        if let Some(username) = self.config.username.as_ref() {
+            push_string(&mut body, username.as_bytes());
+        }
+        if let Some(password) = self.config.password.as_ref() {
+            push_string(&mut body, password.as_bytes());
+        }
+
+        connection.write_all(&packet(0x10, &body))?;
+
+        let mut connack = [0u8; 4];
+        connection.read_exact(&mut connack)?;
+        if connack[0] != 0x20 {
+            return Err(Error::new(ErrorKind::InvalidData, "expected CONNACK"));
+        }
+        if connack[3] != 0 {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("broker refused connection with code {}", connack[3]),
+            ));
+        }
+
+        info!("Connected to MQTT broker {}", self.config.url);
+
+        self.connection = Some(connection);
+        self.announced.clear();
+        self.publish(&will_topic, b"online", true)
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<(), Error> {
+        let mut body = Vec::with_capacity(topic.len() + payload.len() + 2);
+        push_string(&mut body, topic.as_bytes());
+        body.extend_from_slice(payload);
+
+        self.write(&packet(if retain { 0x31 } else { 0x30 }, &body))
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        match self.connection.as_mut() {
+            Some(connection) => {
+                connection.write_all(bytes)?;
+                connection.flush()?;
+                self.last_packet = Instant::now();
+                Ok(())
+            }
+            None => Err(Error::new(ErrorKind::NotConnected, "not connected")),
+        }
+    }
+
+    /// Send a PINGREQ and wait for the PINGRESP, to keep the connection alive
+    /// and notice a broker that went away.
+    fn ping(&mut self) -> Result<(), Error> {
+        self.write(&[0xC0, 0x00])?;
+
+        let mut pingresp = [0u8; 2];
+        if let Some(connection) = self.connection.as_mut() {
+            connection.read_exact(&mut pingresp)?;
+        }
+        if pingresp[0] != 0xD0 {
+            return Err(Error::new(ErrorKind::InvalidData, "expected PINGRESP"));
+        }
+        Ok(())
+    }
+
+    /// Publish the retained Home Assistant discovery config of every entity
+    /// of `sensor` that has a subscribed BSEC output.
+    fn announce(&mut self, sensor: &str) -> Result<(), Error> {
+        let node_id = format!("bme_{}", topic_id(sensor));
+
+        for entity in ENTITIES.iter() {
+            if !entity.kinds.iter().any(|kind| self.kinds.contains(kind)) {
+                continue;
+            }
+
+            let mut config = format!(
+                "{{\"name\":\"{}\",\"unique_id\":\"{}_{}\",\"object_id\":\"{}_{}\",\"state_topic\":\"{}\",\"value_template\":\"{{{{ value_json.{} }}}}\",\"availability_topic\":\"{}\",\"state_class\":\"measurement\"",
+                entity.name,
+                escape(&node_id),
+                entity.key,
+                escape(&node_id),
+                entity.key,
+                escape(&self.state_topic(sensor)),
+                entity.key,
+                escape(&self.availability_topic())
+            );
+            if let Some(device_class) = entity.device_class {
+                config.push_str(&format!(",\"device_class\":\"{}\"", device_class));
+            }
+            if let Some(unit) = entity.unit {
+                config.push_str(&format!(",\"unit_of_measurement\":\"{}\"", unit));
+            }
+            if entity.key.ends_with("_accuracy") {
+                config.push_str(",\"entity_category\":\"diagnostic\"");
+            }
+            config.push_str(&format!(
+                ",\"device\":{{\"identifiers\":[\"{}\"],\"name\":\"BME688 {}\",\"model\":\"BME688\",\"manufacturer\":\"Bosch Sensortec\"}}}}",
+                escape(&node_id),
+                escape(sensor)
+            ));
+
+            let topic = format!(
+                "{}/sensor/{}/{}/config",
+                self.config.discovery_prefix, node_id, entity.key
+            );
+            self.publish(&topic, config.as_bytes(), true)?;
+        }
+
+        info!("Announced sensor {} to Home Assistant", sensor);
+
+        self.announced.insert(String::from(sensor));
+        Ok(())
+    }
+
+    /// Mark the sensors offline and close the connection cleanly, which
+    /// leaves the will unsent.
+    pub fn disconnect(&mut self) {
+        let topic = self.availability_topic();
+        let _ = self.publish(&topic, b"offline", true);
+        let _ = self.write(&[0xE0, 0x00]);
+        self.connection = None;
+    }
+
+    fn publish_reading(&mut self, reading: &Reading) -> Result<(), Error> {
+        if !self.announced.contains(&reading.sensor.name) {
+            self.announce(&reading.sensor.name)?;
+        }
+
+        let payload = build_state(reading, self.min_accuracy);
+        let topic = self.state_topic(&reading.sensor.name);
+        self.publish(&topic, payload.as_bytes(), false)
+    }
+
+    /// Connect unless connected, waiting `RECONNECT_DELAY` between attempts.
+    fn ensure_connected(&mut self) -> Result<(), Error> {
+        if self.connection.is_some() {
+            return Ok(());
+        }
+        if Instant::now() < self.next_connect {
+            return Err(Error::new(ErrorKind::NotConnected, "not connected"));
+        }
+
+        self.next_connect = Instant::now() + RECONNECT_DELAY;
+        let result = self.connect();
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+}
+
+impl Sink for Publisher {
+    fn send(&mut self, reading: &Reading) -> Result<(), Error> {
+        self.ensure_connected()?;
+
+        let result = self.publish_reading(reading);
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+
+    /// Connect as soon as possible so Home Assistant sees the sensors
+    /// online, and ping the broker while idle.
+    fn tick(&mut self) -> Result<(), Error> {
+        if self.connection.is_none() {
+            return self.ensure_connected();
+        }
+
+        if self.last_packet.elapsed() < Duration::from_secs(KEEP_ALIVE_SECS as u64 / 2) {
+            return Ok(());
+        }
+
+        let result = self.ping();
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+
+    fn close(&mut self) {
+        if self.connection.is_some() {
+            debug!("Disconnecting from MQTT broker");
+            self.disconnect();
+        }
+    }
+}
+
+/// JSON state message with one key per output. IAQ and VOC values below
+/// `min_accuracy` are left out, their accuracy is always sent.
+fn build_state(reading: &Reading, min_accuracy: u8) -> String {
+    let mut state = format!("{{\"timestamp\":{}", reading.timestamp / 1_000_000_000);
+    let mut keys: Vec<&str> = Vec::new();
+
+    for output in reading.outputs.iter() {
+        let key = match state_key(output.kind) {
+            Some(key) => key,
+            None => continue,
+        };
+
+        // IAQ and static IAQ share a key, keep the first
+        if keys.contains(&key) {
+            continue;
+        }
+        keys.push(key);
+
+        if let Some(accuracy) = output.accuracy {
+            state.push_str(&format!(",\"{}_accuracy\":{}", key, accuracy));
+
+            if output.below_accuracy(min_accuracy) {
+                continue;
+            }
+        }
+
+        let value = if output.kind == OutputKind::RawPressure {
+            // Pa to hPa
+            output.value / 100.0
+        } else {
+            output.value
+        };
+
+        if value.is_finite() {
+            state.push_str(&format!(",\"{}\":{}", key, value));
+        }
+    }
+
+    state.push('}');
+    state
+}
+
+/// Append a length prefixed MQTT string.
+/// `sensor` as a topic level and Home Assistant node id, which only allow
+/// letters, digits, `_` and `-`. The raw name is only used in the JSON.
+fn topic_id(sensor: &str) -> String {
+    sensor
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Connect to the first address of `url` that answers, so an unreachable
+/// broker does not block the sink thread for minutes.
+fn connect(url: &str) -> Result<TcpStream, Error> {
+    let mut last_error = Error::new(ErrorKind::NotFound, "no address found");
+    for address in url.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(connection) => return Ok(connection),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+fn push_string(buffer: &mut Vec<u8>, string: &[u8]) {
+    buffer.extend_from_slice(&(string.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(string);
+}
+
+/// Frame `body` with the fixed header of an MQTT packet.
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(body.len() + 5);
+    packet.push(header);
+
+    // Remaining length, 7 bits per byte
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+
+    packet.extend_from_slice(body);
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{Output, SensorId};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Fixed header and body of the next packet.
+    fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte).unwrap();
+        let header = byte[0];
+
+        let mut length = 0;
+        let mut shift = 0;
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            length |= ((byte[0] & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let mut body = vec![0u8; length];
+        stream.read_exact(&mut body).unwrap();
+        (header, body)
+    }
+
+    /// Topic and payload of a QoS 0 PUBLISH.
+    fn split_publish(body: &[u8]) -> (String, String) {
+        let length = u16::from_be_bytes([body[0], body[1]]) as usize;
+        let topic = String::from_utf8(body[2..2 + length].to_vec()).unwrap();
+        let payload = String::from_utf8(body[2 + length..].to_vec()).unwrap();
+        (topic, payload)
+    }
+
+    #[test]
+    fn publishes_to_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = MqttConfig {
+            url: listener.local_addr().unwrap().to_string(),
+            username: None,
+            password: None,
+            client_id: String::from("bme-test"),
+            topic_prefix: String::from("bme"),
+            discovery_prefix: String::from("homeassistant"),
+        };
+
+        // Stand-in broker, accepting one connection until DISCONNECT
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let connect = read_packet(&mut stream);
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+
+            let mut packets = Vec::new();
+            loop {
+                let packet = read_packet(&mut stream);
+                if packet.0 == 0xE0 {
+                    break;
+                }
+                packets.push(packet);
+            }
+            (connect, packets)
+        });
+
+        let kinds = vec![
+            OutputKind::Iaq,
+            OutputKind::StaticIaq,
+            OutputKind::RawPressure,
+        ];
+        let reading = Reading {
+            sensor: SensorId {
+                name: String::from("my \"study\"/+"),
+                bus: String::from("i2c-1"),
+                address: 0x77,
+                location: None,
+            },
+            timestamp: 1_700_000_000_000_000_000,
+            outputs: vec![
+                Output {
+                    kind: OutputKind::Iaq,
+                    value: 50.0,
+                    accuracy: Some(3),
+                },
+                Output {
+                    kind: OutputKind::StaticIaq,
+                    value: 60.0,
+                    accuracy: Some(2),
+                },
+                Output {
+                    kind: OutputKind::RawPressure,
+                    value: 101325.0,
+                    accuracy: None,
+                },
+            ],
+        };
+
+        let mut publisher = Publisher::new(config, kinds, 0);
+        publisher.send(&reading).unwrap();
+        publisher.close();
+
+        let ((connect_header, connect), packets) = broker.join().unwrap();
+
+        // MQTT 3.1.1, clean session with a retained QoS 0 will, keep alive
+        assert_eq!(connect_header, 0x10);
+        let mut expected = vec![0, 4, b'M', b'Q', b'T', b'T', 4, 0b0010_0110, 0, 60];
+        push_string(&mut expected, b"bme-test");
+        push_string(&mut expected, b"bme/status");
+        push_string(&mut expected, b"offline");
+        assert_eq!(connect, expected);
+
+        let publishes: Vec<(u8, String, String)> = packets
+            .iter()
+            .map(|(header, body)| {
+                let (topic, payload) = split_publish(body);
+                (*header, topic, payload)
+            })
+            .collect();
+
+        assert_eq!(
+            publishes[0],
+            (0x31, String::from("bme/status"), String::from("online"))
+        );
+
+        // Retained discovery of the subscribed entities only
+        let discovery: Vec<&(u8, String, String)> = publishes
+            .iter()
+            .filter(|(_, topic, _)| topic.starts_with("homeassistant/"))
+            .collect();
+        let topics: Vec<&str> = discovery
+            .iter()
+            .map(|(_, topic, _)| topic.as_str())
+            .collect();
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/sensor/bme_my__study___/pressure/config",
+                "homeassistant/sensor/bme_my__study___/iaq/config",
+                "homeassistant/sensor/bme_my__study___/iaq_accuracy/config",
+            ]
+        );
+        for (header, _, config) in discovery.iter() {
+            assert_eq!(*header, 0x31);
+            assert!(config.contains("\"state_topic\":\"bme/my__study___/state\""));
+            assert!(config.contains("\"unique_id\":\"bme_my__study____"));
+            assert!(config.contains("\"name\":\"BME688 my \\\"study\\\"/+\""));
+        }
+
+        // State without retain, with the IAQ from the first IAQ output
+        let state: Vec<&(u8, String, String)> = publishes
+            .iter()
+            .filter(|(_, topic, _)| topic.ends_with("/state"))
+            .collect();
+        assert_eq!(state.len(), 1);
+        assert_eq!(state[0].0, 0x30);
+        assert_eq!(state[0].1, "bme/my__study___/state");
+        assert_eq!(
+            state[0].2,
+            "{\"timestamp\":1700000000,\"iaq_accuracy\":3,\"iaq\":50,\"pressure\":1013.25}"
+        );
+
+        assert_eq!(
+            publishes.last().unwrap(),
+            &(0x31, String::from("bme/status"), String::from("offline"))
+        );
+    }
+}
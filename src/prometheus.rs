@@ -0,0 +1,461 @@
+use crate::graphite;
+use crate::sink::{Output, OutputKind, Reading, SensorId, SensorStatus, Sink};
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Error, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Prometheus name and help text of each BSEC output. Gas estimates share one
+/// metric, told apart by their `class` label.
+const METRICS: [(OutputKind, &str, &str); 19] = [
+    (
+        OutputKind::Iaq,
+        "bme_iaq",
+        "Indoor air quality index, 0 to 500.",
+    ),
+    (
+        OutputKind::StaticIaq,
+        "bme_static_iaq",
+        "Indoor air quality index for stationary devices, 0 to 500.",
+    ),
+    (
+        OutputKind::Co2Equivalent,
+        "bme_co2_equivalent_ppm",
+        "Estimated CO2 concentration in ppm.",
+    ),
+    (
+        OutputKind::BreathVocEquivalent,
+        "bme_breath_voc_equivalent_ppm",
+        "Estimated breath VOC concentration in ppm.",
+    ),
+    (
+        OutputKind::RawTemperature,
+        "bme_raw_temperature_celsius",
+        "Temperature measured by the sensor in degrees Celsius.",
+    ),
+    (
+        OutputKind::RawPressure,
+        "bme_pressure_pascals",
+        "Pressure in Pascal.",
+    ),
+    (
+        OutputKind::RawHumidity,
+        "bme_raw_humidity_percent",
+        "Relative humidity measured by the sensor in percent.",
+    ),
+    (
+        OutputKind::RawGas,
+        "bme_gas_resistance_ohms",
+        "Gas sensor resistance in Ohm.",
+    ),
+    (
+        OutputKind::RawGasIndex,
+        "bme_gas_index",
+        "Heater profile step of the last gas measurement.",
+    ),
+    (
+        OutputKind::StabilizationStatus,
+        "bme_stabilization_status",
+        "1 once the gas sensor has stabilized.",
+    ),
+    (
+        OutputKind::RunInStatus,
+        "bme_run_in_status",
+        "1 once the gas sensor run-in has finished.",
+    ),
+    (
+        OutputKind::HeatCompensatedTemperature,
+        "bme_temperature_celsius",
+        "Temperature compensated for sensor heating in degrees Celsius.",
+    ),
+    (
+        OutputKind::HeatCompensatedHumidity,
+        "bme_humidity_percent",
+        "Relative humidity compensated for sensor heating in percent.",
+    ),
+    (
+        OutputKind::CompensatedGas,
+        "bme_compensated_gas_log_ohms",
+        "Log10 of the gas resistance compensated for temperature and humidity.",
+    ),
+    (
+        OutputKind::GasPercentage,
+        "bme_gas_percentage_percent",
+        "Gas resistance relative to its recent range in percent.",
+    ),
+    (
+        OutputKind::GasEstimate(0),
+        "bme_gas_estimate_probability",
+        "Probability of each gas class in scan mode, 0 to 1.",
+    ),
+    (
+        OutputKind::GasEstimate(1),
+        "bme_gas_estimate_probability",
+        "Probability of each gas class in scan mode, 0 to 1.",
+    ),
+    (
+        OutputKind::GasEstimate(2),
+        "bme_gas_estimate_probability",
+        "Probability of each gas class in scan mode, 0 to 1.",
+    ),
+    (
+        OutputKind::GasEstimate(3),
+        "bme_gas_estimate_probability",
+        "Probability of each gas class in scan mode, 0 to 1.",
+    ),
+];
+
+#[derive(Default)]
+struct SensorMetrics {
+    /// Latest value of each output.
+    outputs: BTreeMap<OutputKind, Output>,
+    last_read: Option<i64>,
+    bsec_return_code: i32,
+}
+
+/// Latest BSEC outputs of every sensor, served on `/metrics`.
+#[derive(Clone)]
+pub struct Exporter {
+    sensors: Arc<Mutex<BTreeMap<String, SensorMetrics>>>,
+    min_accuracy: u8,
+    gas_labels: Vec<String>,
+    graphite: Option<graphite::Stats>,
+    /// Address the server is bound to, and whether it should stop.
+    local_address: Option<SocketAddr>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Exporter {
+    pub fn new(min_accuracy: u8, gas_labels: Vec<String>) -> Exporter {
+        Exporter {
+            sensors: Arc::new(Mutex::new(BTreeMap::new())),
+            min_accuracy,
+            gas_labels,
+            graphite: None,
+            local_address: None,
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Also report the spool of the Graphite client.
+    pub fn with_graphite_stats(mut self, stats: graphite::Stats) -> Exporter {
+        self.graphite = Some(stats);
+        self
+    }
+
+    /// Store the outputs of one reading. Outputs not in this reading keep
+    /// their previous value, as BSEC runs them at different rates.
+    pub fn update(&self, reading: &Reading) {
+        let mut sensors = self.sensors.lock().unwrap();
+        let metrics = sensors.entry(reading.sensor.name.clone()).or_default();
+        for output in reading.outputs.iter() {
+            metrics.outputs.insert(output.kind, *output);
+        }
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let sensors = self.sensors.lock().unwrap();
+        let mut text = String::new();
+        let mut last_name = "";
+
+        for (kind, name, help) in METRICS.iter() {
+            for (sensor, metrics) in sensors.iter() {
+                let output = match metrics.outputs.get(kind) {
+                    Some(output) => output,
+                    None => continue,
+                };
+
+                if output.below_accuracy(self.min_accuracy) {
+                    continue;
+                }
+
+                if *name != last_name {
+                    write_header(&mut text, name, help);
+                    last_name = name;
+                }
+
+                let labels = match kind {
+                    OutputKind::GasEstimate(index) => format!(
+                        "sensor=\"{}\",class=\"{}\"",
+                        escape_label(sensor),
+                        escape_label(&self.gas_labels[*index])
+                    ),
+                    _ => format!("sensor=\"{}\"", escape_label(sensor)),
+                };
+
+                let _ = writeln!(text, "{}{{{}}} {}", name, labels, output.value);
+            }
+        }
+
+        write_header(
+            &mut text,
+            "bme_accuracy",
+            "Calibration accuracy of an output, 0 (stabilizing) to 3 (calibrated).",
+        );
+        for (sensor, metrics) in sensors.iter() {
+            for output in metrics.outputs.values() {
+                if let Some(accuracy) = output.accuracy {
+                    let _ = writeln!(
+                        text,
+                        "bme_accuracy{{sensor=\"{}\",output=\"{}\"}} {}",
+                        escape_label(sensor),
+                        output.kind.name(),
+                        accuracy
+                    );
+                }
+            }
+        }
+
+        write_header(
+            &mut text,
+            "bme_last_read_timestamp_seconds",
+            "Unix time of the last valid reading from the sensor.",
+        );
+        for (sensor, metrics) in sensors.iter() {
+            if let Some(last_read) = metrics.last_read {
+                let _ = writeln!(
+                    text,
+                    "bme_last_read_timestamp_seconds{{sensor=\"{}\"}} {}",
+                    escape_label(sensor),
+                    last_read as f64 / 1e9
+                );
+            }
+        }
+
+        write_header(
+            &mut text,
+            "bme_bsec_return_code",
+            "Return code of the last BSEC call, 0 when OK, negative for errors and positive for warnings.",
+        );
+        for (sensor, metrics) in sensors.iter() {
+            let _ = writeln!(
+                text,
+                "bme_bsec_return_code{{sensor=\"{}\"}} {}",
+                escape_label(sensor),
+                metrics.bsec_return_code
+            );
+        }
+
+        if let Some(stats) = self.graphite.as_ref() {
+            write_graphite_stats(&mut text, stats);
+        }
+
+        text
+    }
+
+    /// Serve `/metrics` on `address` from a background thread.
+    pub fn serve(&mut self, address: &str) -> Result<(), Error> {
+        let listener = TcpListener::bind(address)?;
+        self.local_address = Some(listener.local_addr()?);
+
+        info!("Serving Prometheus metrics on http://{}/metrics", address);
+
+        let exporter = self.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if exporter.stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = exporter.respond(stream) {
+                            debug!("Failed to answer metrics request: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("Failed to accept metrics connection: {}", e),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    fn respond(&self, mut stream: TcpStream) -> Result<(), Error> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+
+        // Skip the headers, the request has no body
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.render()),
+            (Some("GET"), _) => ("404 Not Found", String::from("Not Found\n")),
+            _ => (
+                "405 Method Not Allowed",
+                String::from("Method Not Allowed\n"),
+            ),
+        };
+
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+        stream.flush()
+    }
+}
+
+impl Sink for Exporter {
+    fn send(&mut self, reading: &Reading) -> Result<(), Error> {
+        self.update(reading);
+        Ok(())
+    }
+
+    fn status(&mut self, sensor: &SensorId, status: &SensorStatus) -> Result<(), Error> {
+        let mut sensors = self.sensors.lock().unwrap();
+        let metrics = sensors.entry(sensor.name.clone()).or_default();
+        if status.last_read.is_some() {
+            metrics.last_read = status.last_read;
+        }
+        metrics.bsec_return_code = status.bsec_return_code;
+        Ok(())
+    }
+
+    /// Drop the series of the sensor, rather than export its last values as
+    /// if it were still measuring.
+    fn remove(&mut self, sensor: &SensorId) {
+        self.sensors.lock().unwrap().remove(&sensor.name);
+    }
+
+    /// Stop the server, so the address can be bound again after a reload.
+    fn close(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+
+        // Wake the server up from waiting for a connection
+        if let Some(mut address) = self.local_address {
+            match address.ip() {
+                IpAddr::V4(ip) if ip.is_unspecified() => address.set_ip(Ipv4Addr::LOCALHOST.into()),
+                IpAddr::V6(ip) if ip.is_unspecified() => address.set_ip(Ipv6Addr::LOCALHOST.into()),
+                _ => {}
+            }
+            let _ = TcpStream::connect_timeout(&address, Duration::from_secs(1));
+        }
+    }
+}
+
+fn write_graphite_stats(text: &mut String, stats: &graphite::Stats) {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+    write_header(
+        text,
+        "bme_graphite_spooled_points",
+        "Graphite points waiting in the spool for the server to come back.",
+    );
+    let _ = writeln!(
+        text,
+        "bme_graphite_spooled_points {}",
+        load(&stats.spooled_points)
+    );
+
+    for (name, help, value) in [
+        (
+            "bme_graphite_dropped_points_total",
+            "Graphite points dropped for age or because the spool or queue was full.",
+            load(&stats.dropped_points),
+        ),
+        (
+            "bme_graphite_sent_points_total",
+            "Graphite points sent to the server.",
+            load(&stats.sent_points),
+        ),
+        (
+            "bme_graphite_errors_total",
+            "Failed connects and sends to the Graphite server.",
+            load(&stats.errors),
+        ),
+    ] {
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} counter", name);
+        let _ = writeln!(text, "{} {}", name, value);
+    }
+
+    let _ = writeln!(
+        text,
+        "# HELP bme_graphite_batch_duration_seconds Time taken to send a batch to the Graphite server."
+    );
+    let _ = writeln!(text, "# TYPE bme_graphite_batch_duration_seconds summary");
+    let _ = writeln!(
+        text,
+        "bme_graphite_batch_duration_seconds_sum {}",
+        load(&stats.batch_micros) as f64 / 1e6
+    );
+    let _ = writeln!(
+        text,
+        "bme_graphite_batch_duration_seconds_count {}",
+        load(&stats.sent_batches)
+    );
+}
+
+/// Escape backslash, double quote and line feed in a label value.
+fn escape_label(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn write_header(text: &mut String, name: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} gauge", name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sensor(name: &str) -> SensorId {
+        SensorId {
+            name: String::from(name),
+            bus: String::from("i2c-1"),
+            address: 0x77,
+            location: None,
+        }
+    }
+
+    #[test]
+    fn removed_sensor_is_not_exported() {
+        let mut exporter = Exporter::new(0, Vec::new());
+        for name in ["study", "bedroom"] {
+            let reading = Reading {
+                sensor: sensor(name),
+                timestamp: 1_700_000_000_000_000_000,
+                outputs: vec![Output {
+                    kind: OutputKind::HeatCompensatedTemperature,
+                    value: 21.5,
+                    accuracy: None,
+                }],
+            };
+            exporter.send(&reading).unwrap();
+        }
+        assert!(exporter
+            .render()
+            .contains("bme_temperature_celsius{sensor=\"bedroom\"} 21.5"));
+
+        exporter.remove(&sensor("bedroom"));
+        let text = exporter.render();
+        assert!(text.contains("bme_temperature_celsius{sensor=\"study\"} 21.5"));
+        assert!(!text.contains("bedroom"));
+    }
+}
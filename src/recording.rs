@@ -0,0 +1,127 @@
+use bme68x_rust::SensorData;
+use log::{info, warn};
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+const HEADER: &str = "timestamp,temperature,humidity,pressure,gas_resistance,gas_index,status";
+
+/// A raw sensor reading as fed into BSEC, with the nanosecond timestamp it
+/// was taken at.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RawReading {
+    pub timestamp: i64,
+    pub temperature: f32,
+    pub humidity: f32,
+    pub pressure: f32,
+    pub gas_resistance: f32,
+    pub gas_index: u8,
+    pub status: u8,
+}
+
+impl RawReading {
+    pub fn new(timestamp: i64, data: &SensorData) -> RawReading {
+        RawReading {
+            timestamp,
+            temperature: data.temperature,
+            humidity: data.humidity,
+            pressure: data.pressure,
+            gas_resistance: data.gas_resistance,
+            gas_index: data.gas_index,
+            status: data.status,
+        }
+    }
+
+    fn to_line(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{}",
+            self.timestamp,
+            self.temperature,
+            self.humidity,
+            self.pressure,
+            self.gas_resistance,
+            self.gas_index,
+            self.status
+        )
+    }
+
+    fn parse(line: &str) -> Result<RawReading, Error> {
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+        if fields.len() != 7 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("expected 7 fields, found {}", fields.len()),
+            ));
+        }
+
+        Ok(RawReading {
+            timestamp: parse_field(fields[0])?,
+            temperature: parse_field(fields[1])?,
+            humidity: parse_field(fields[2])?,
+            pressure: parse_field(fields[3])?,
+            gas_resistance: parse_field(fields[4])?,
+            gas_index: parse_field(fields[5])?,
+            status: parse_field(fields[6])?,
+        })
+    }
+}
+
+fn parse_field<T: FromStr>(field: &str) -> Result<T, Error>
+where
+    T::Err: Display,
+{
+    field
+        .parse()
+        .map_err(|e: T::Err| Error::new(ErrorKind::InvalidData, format!("'{}': {}", field, e)))
+}
+
+/// Appends every raw reading to a CSV file.
+pub struct Recorder {
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn open(path: &Path) -> Result<Recorder, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let is_empty = file.metadata()?.len() == 0;
+
+        let mut writer = BufWriter::new(file);
+        if is_empty {
+            writeln!(writer, "{}", HEADER)?;
+        }
+
+        info!("Recording raw readings to {}", path.display());
+
+        Ok(Recorder { writer })
+    }
+
+    pub fn record(&mut self, reading: &RawReading) -> Result<(), Error> {
+        writeln!(self.writer, "{}", reading.to_line())?;
+        // Flush every line so a crash only loses the current reading
+        self.writer.flush()
+    }
+}
+
+/// Reads back a file written by `Recorder`, skipping the header and any
+/// lines that fail to parse.
+pub fn read_recording(path: &Path) -> Result<Vec<RawReading>, Error> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut readings = Vec::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with(HEADER) || line.starts_with('#') {
+            continue;
+        }
+        match RawReading::parse(&line) {
+            Ok(reading) => readings.push(reading),
+            Err(e) => warn!("Skipping line {} of {}: {}", i + 1, path.display(), e),
+        }
+    }
+
+    info!("Loaded {} readings from {}", readings.len(), path.display());
+
+    Ok(readings)
+}
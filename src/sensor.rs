@@ -0,0 +1,587 @@
+use crate::bme::{self, I2cDriver, Reopen};
+use crate::bsec::{self, bsec_virtual_sensor_t, Bsec};
+use crate::config::{Config, RecoveryConfig, StateSaveConfig};
+use crate::recording::{RawReading, Recorder};
+use crate::sink::{Reading, SensorId, SensorStatus, Sinks};
+use crate::state;
+use bme68x_rust::{
+    Device, DeviceConfig, Error as BmeError, Filter, GasHeaterConfig, Interface, Odr,
+    OperationMode, SensorData,
+};
+use chrono::{Local, NaiveDateTime, Utc};
+use log::{debug, info, warn};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Metric prefix used when there is only one, unnamed sensor.
+pub const DEFAULT_NAME: &str = "study";
+
+/// Length of one heater profile step in parallel mode, shared between the
+/// TPH measurement and heating.
+const TOTAL_HEAT_DURATION_MS: u32 = 140;
+
+/// One BME68x with its own BSEC instance and state file.
+pub struct Sensor<I: Interface> {
+    pub id: SensorId,
+    pub state_file: PathBuf,
+    pub bsec: Bsec,
+    pub next_call: i64,
+    bme: Device<I>,
+    recorder: Option<Recorder>,
+    state_save: StateSaveConfig,
+    last_saved: Instant,
+    /// Lowest accuracy of the last BSEC outputs that report one.
+    accuracy: Option<u8>,
+    /// Latest IAQ, or static IAQ, and its accuracy.
+    pub iaq: Option<(f32, u8)>,
+    recovery: RecoveryConfig,
+    /// Failed measurements in a row, and when the first of them failed.
+    failures: u32,
+    failing_since: Option<Instant>,
+}
+
+impl<I: Reopen> Sensor<I> {
+    pub fn new(
+        id: SensorId,
+        state_file: PathBuf,
+        bme: Device<I>,
+        record_file: Option<PathBuf>,
+        config: &Config,
+    ) -> std::io::Result<Sensor<I>> {
+        info!("Setting up sensor {}", id.name);
+
+        let mut bsec_state = init_bsec(config)?;
+
+        // Load BSEC last state, falling back to the backups
+
+        load_state(
+            &id.name,
+            &state_file,
+            &mut bsec_state,
+            config.state_save.backups,
+        );
+
+        // Setup sensor config
+
+        info!("Running in {} mode", bsec::mode_name(config.sample_rate));
+
+        bsec_state.update_subscription(config.sample_rate, &config.outputs)?;
+
+        let recorder = match record_file {
+            Some(record_file) => Some(Recorder::open(&record_file)?),
+            None => None,
+        };
+
+        Ok(Sensor {
+            id,
+            state_file,
+            bsec: bsec_state,
+            next_call: 0,
+            bme,
+            recorder,
+            state_save: config.state_save,
+            last_saved: Instant::now(),
+            accuracy: None,
+            iaq: None,
+            recovery: config.recovery,
+            failures: 0,
+            failing_since: None,
+        })
+    }
+
+    /// Run one BSEC sensor control cycle and update `next_call`. A sensor
+    /// that cannot be read is tried again every second and reset every
+    /// `reset_after` failures in a row. The error means it has failed for
+    /// longer than `give_up_after`.
+    pub fn measure(&mut self, sinks: &Sinks) -> Result<(), Error> {
+        let error = match self.control_cycle(sinks) {
+            Ok(()) => {
+                if self.failures > 0 {
+                    info!(
+                        "Sensor {} recovered after {} failed measurements.",
+                        self.id.name, self.failures
+                    );
+                    self.failures = 0;
+                    self.failing_since = None;
+                }
+                return Ok(());
+            }
+            Err(error) => error,
+        };
+
+        self.failures += 1;
+        let failing_since = *self.failing_since.get_or_insert_with(Instant::now);
+
+        warn!(
+            sensor = self.id.name.as_str(), address = self.id.address;
+            "Measurement of {} failed, {} in a row: {:?}", self.id.name, self.failures, error
+        );
+
+        sinks.status(
+            &self.id,
+            SensorStatus {
+                last_read: None,
+                bsec_return_code: self.bsec.last_result(),
+            },
+        );
+
+        if let Some(timeout) = self.recovery.give_up_after {
+            if failing_since.elapsed() >= timeout {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!(
+                        "Sensor {} failed for {} s: {:?}",
+                        self.id.name,
+                        timeout.as_secs(),
+                        error
+                    ),
+                ));
+            }
+        }
+
+        if self.failures % self.recovery.reset_after == 0 {
+            self.reset();
+        }
+
+        // Try again in a second
+        self.next_call = Local::now().naive_utc().timestamp_nanos() + 1_000_000_000;
+
+        Ok(())
+    }
+
+    /// Soft reset the sensor and initialize it again on a newly opened bus,
+    /// keeping the BSEC state.
+    fn reset(&mut self) {
+        warn!(
+            sensor = self.id.name.as_str(), address = self.id.address;
+            "Resetting sensor {}", self.id.name
+        );
+
+        if let Err(e) = self.bme.soft_reset() {
+            debug!("Soft reset of {} failed: {:?}", self.id.name, e);
+        }
+
+        match self.bme.interface.reopen().and_then(Device::initialize) {
+            Ok(bme) => {
+                self.bme = bme;
+                info!("Sensor {} initialized again.", self.id.name);
+            }
+            Err(e) => warn!("Cannot initialize sensor {} again: {:?}", self.id.name, e),
+        }
+    }
+
+    /// Ask BSEC what to do, take a measurement if it asks for one and feed
+    /// it to BSEC.
+    fn control_cycle(&mut self, sinks: &Sinks) -> Result<(), BmeError> {
+        let bsec_state = &mut self.bsec;
+        let bme = &mut self.bme;
+
+        let start_timestamp = Local::now().naive_utc().timestamp_nanos();
+        let mut last_read = None;
+
+        info!("Calling {} at: {}", self.id.name, Local::now());
+
+        match bsec_state.get_sensor_config(start_timestamp) {
+            Err(e) if !e.is_warning() => {
+                // Try again in a second
+                self.next_call = start_timestamp + 1_000_000_000;
+                sinks.status(
+                    &self.id,
+                    SensorStatus {
+                        last_read: None,
+                        bsec_return_code: bsec_state.last_result(),
+                    },
+                );
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        bme.set_config(
+            DeviceConfig::default()
+                .filter(Filter::Size3)
+                .odr(Odr::StandbyNone)
+                .oversample_humidity(bsec_state.sensor_settings.humidity_oversampling.into())
+                .oversample_temperature(bsec_state.sensor_settings.temperature_oversampling.into())
+                .oversample_pressure(bsec_state.sensor_settings.pressure_oversampling.into()),
+        )?;
+
+        let mut heater_config = GasHeaterConfig::default()
+            .enable()
+            .heater_temp(bsec_state.sensor_settings.heater_temperature)
+            .heater_duration(bsec_state.sensor_settings.heater_duration)
+            .heater_temp_profile(
+                bsec_state
+                    .sensor_settings
+                    .heater_temperature_profile
+                    .as_mut_ptr(),
+            )
+            .heater_dur_profile(
+                bsec_state
+                    .sensor_settings
+                    .heater_duration_profile
+                    .as_mut_ptr(),
+            )
+            .profile_len(bsec_state.sensor_settings.heater_profile_len);
+
+        if bsec_state.parallel_mode() {
+            // The heater steps share what is left of the heating period after the TPH measurement
+            let measure_duration =
+                bme.get_measure_duration(bsec_state.sensor_settings.op_mode.into()) / 1000;
+            heater_config = heater_config.shared_heater_duration(
+                TOTAL_HEAT_DURATION_MS.saturating_sub(measure_duration) as u16,
+            );
+        }
+
+        bme.set_gas_heater_conf(bsec_state.sensor_settings.op_mode.into(), heater_config)?;
+
+        // -------------------------------------------------------
+
+        if bsec_state.sensor_settings.trigger_measurement == 1 {
+            bme.set_op_mode(bsec_state.sensor_settings.op_mode.into())?;
+
+            let delay_period = bme.get_measure_duration(bsec_state.sensor_settings.op_mode.into());
+            bme.interface.delay(delay_period);
+
+            let mut measure_results = bme.get_data(bsec_state.sensor_settings.op_mode.into());
+
+            // Read data from sensor until valid measurement is obtained
+
+            for i in 1..100 {
+                if measure_results.is_ok() && // no new data
+                 measure_results.as_ref().unwrap()[0].status & 0b10000 == 0b10000 && // heater stable
+                    measure_results.as_ref().unwrap()[0].status & 0b100000 == 0b100000
+                // gas measurement valid
+                {
+                    break;
+                }
+
+                if i == 50 {
+                    info!(
+                        sensor = self.id.name.as_str(), address = self.id.address;
+                        "Sensor {} not ready, polling again...", self.id.name
+                    );
+                }
+
+                bme.interface.delay(10000);
+
+                measure_results = bme.get_data(bsec_state.sensor_settings.op_mode.into());
+            }
+
+            let measure_results = measure_results?;
+
+            last_read = Some(start_timestamp);
+
+            // In parallel mode every valid field is one step of the heater profile
+            let fields: Vec<&SensorData> = if bsec_state.parallel_mode() {
+                measure_results
+                    .iter()
+                    .filter(|data| data.status & 0b10110000 == 0b10110000)
+                    .collect()
+            } else {
+                vec![&measure_results[0]]
+            };
+
+            for field in fields {
+                debug!("{:#?}", field);
+
+                let reading = RawReading::new(start_timestamp, field);
+
+                if let Some(recorder) = self.recorder.as_mut() {
+                    recorder
+                        .record(&reading)
+                        .unwrap_or_else(|e| warn!("Failed to record reading: {}", e));
+                }
+
+                let sensor_inputs = bsec_state.process_data(&reading);
+
+                debug!("{:?}", sensor_inputs);
+
+                if let Ok(sensor_outputs) = bsec_state.do_steps(&sensor_inputs) {
+                    let accuracy = sensor_outputs
+                        .iter()
+                        .filter(|output| bsec::reports_accuracy(output.sensor_id))
+                        .map(|output| output.accuracy)
+                        .min();
+                    if accuracy.is_some() {
+                        self.accuracy = accuracy;
+                    }
+
+                    let iaq = [
+                        bsec_virtual_sensor_t::BSEC_OUTPUT_IAQ,
+                        bsec_virtual_sensor_t::BSEC_OUTPUT_STATIC_IAQ,
+                    ]
+                    .iter()
+                    .find_map(|id| {
+                        sensor_outputs
+                            .iter()
+                            .find(|output| u32::from(output.sensor_id) == *id)
+                    });
+                    if let Some(iaq) = iaq {
+                        self.iaq = Some((iaq.signal, iaq.accuracy));
+                    }
+
+                    sinks.send(Reading::new(&self.id, start_timestamp, &sensor_outputs));
+                }
+            }
+        }
+
+        // ---------------------------------------------
+
+        self.next_call = bsec_state.sensor_settings.next_call;
+
+        sinks.status(
+            &self.id,
+            SensorStatus {
+                last_read,
+                bsec_return_code: bsec_state.last_result(),
+            },
+        );
+
+        info!(
+            sensor = self.id.name.as_str(),
+            address = self.id.address,
+            bsec_return_code = self.bsec.last_result();
+            "Next call time for {}: {}",
+            self.id.name,
+            NaiveDateTime::from_timestamp_opt(
+                self.next_call / 1000 / 1000 / 1000,
+                (self.next_call % 1000000000) as u32
+            )
+            .unwrap()
+            .and_local_timezone(Utc)
+            .unwrap()
+            .with_timezone(&Local::now().timezone())
+        );
+
+        // Save the calibration now and then, not only on exit
+
+        if let Some(interval) = self.state_save.interval {
+            let calibrated =
+                !matches!(self.accuracy, Some(accuracy) if accuracy < self.state_save.min_accuracy);
+            if self.last_saved.elapsed() >= interval && calibrated {
+                self.save_state();
+                self.last_saved = Instant::now();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the current BSEC state to the state file, see `state::write`.
+    pub fn save_state(&mut self) {
+        let serialized_state = match self.bsec.get_state() {
+            Ok(serialized_state) => serialized_state,
+            Err(e) => {
+                warn!("Error getting BSEC state of {}: {}", self.id.name, e);
+                return;
+            }
+        };
+
+        match state::write(
+            &self.state_file,
+            &serialized_state,
+            &self.bsec.version,
+            self.state_save.backups,
+        ) {
+            Ok(()) => info!("BSEC state of {} saved.", self.id.name),
+            Err(e) => warn!(
+                "Error saving BSEC state of {} to {}: {}",
+                self.id.name,
+                self.state_file.display(),
+                e
+            ),
+        }
+    }
+
+    /// Apply a reloaded config, keeping the BSEC state and the device.
+    pub fn reload(&mut self, config: &Config, resubscribe: bool) -> std::io::Result<()> {
+        // Subscribe first, so a sensor BSEC refuses the outputs for is left
+        // as it was
+        if resubscribe {
+            info!(
+                "Running {} in {} mode",
+                self.id.name,
+                bsec::mode_name(config.sample_rate)
+            );
+            self.bsec
+                .update_subscription(config.sample_rate, &config.outputs)?;
+            // Ask BSEC for the new sensor settings right away
+            self.next_call = 0;
+        }
+
+        self.bsec.temperature_offset = config.temperature_offset;
+        self.state_save = config.state_save;
+        self.recovery = config.recovery;
+
+        Ok(())
+    }
+
+    /// Save the state and put the sensor to sleep, with the heater off.
+    pub fn shutdown(&mut self) {
+        self.save_state();
+
+        if let Err(e) = self.bme.set_op_mode(OperationMode::Sleep) {
+            warn!("Cannot put sensor {} to sleep: {:?}", self.id.name, e);
+        }
+    }
+}
+
+pub fn init_bsec(config: &Config) -> std::io::Result<Bsec> {
+    let mut bsec_state = Bsec::init()?;
+
+    bsec_state.get_version()?;
+
+    bsec_state.temperature_offset = config.temperature_offset;
+
+    if let Some(config_file) = config.bsec_config_file.as_ref() {
+        let serialized_settings = bsec::load_configuration(config_file)?;
+
+        bsec_state.set_configuration(&serialized_settings)?;
+
+        info!("BSEC config loaded from {}", config_file.display());
+    }
+
+    Ok(bsec_state)
+}
+
+/// Load the newest state file BSEC accepts, starting uncalibrated if there is
+/// none.
+fn load_state(name: &str, state_file: &Path, bsec_state: &mut Bsec, backups: usize) {
+    for file in state::candidates(state_file, backups) {
+        let saved = match state::read(&file) {
+            Ok(saved) => saved,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("Last BSEC state not found at {}.", file.display());
+                continue;
+            }
+            Err(e) => {
+                warn!("Cannot read BSEC state {}: {}", file.display(), e);
+                continue;
+            }
+        };
+
+        let current = state::version_bytes(&bsec_state.version);
+        match saved.bsec_version {
+            Some(version) if version != current => warn!(
+                "BSEC state {} was saved by BSEC {}, running {}",
+                file.display(),
+                state::version_name(version),
+                state::version_name(current)
+            ),
+            _ => {}
+        }
+
+        match bsec_state.set_state(&saved.blob) {
+            Ok(()) => {
+                info!("BSEC state of {} loaded from {}", name, file.display());
+                return;
+            }
+            Err(e) => warn!("BSEC state {} rejected: {}", file.display(), e),
+        }
+    }
+
+    warn!("No usable BSEC state for {}, starting uncalibrated.", name);
+}
+
+/// A BME68x found on an i2c bus, named and ready to be set up.
+pub struct FoundSensor {
+    pub id: SensorId,
+    pub state_file: PathBuf,
+    pub device: Device<I2cDriver>,
+}
+
+/// Initialize the configured sensors, or all that can be found if none are
+/// configured, and name them.
+pub fn find_sensors(config: &Config) -> Vec<FoundSensor> {
+    let devices: Vec<(Option<String>, Option<String>, Device<I2cDriver>)> =
+        if config.sensors.is_empty() {
+            bme::discover()
+                .into_iter()
+                .map(|device| (None, None, device))
+                .collect()
+        } else {
+            config
+                .sensors
+                .iter()
+                .filter_map(|sensor| {
+                    bme::create_device(&sensor.path, sensor.address)
+                        .and_then(bme::init)
+                        .map(|device| (sensor.name.clone(), sensor.location.clone(), device))
+                })
+                .collect()
+        };
+
+    if !config.sensors.is_empty() && devices.len() < config.sensors.len() {
+        warn!(
+            "Only {} of {} configured sensors found.",
+            devices.len(),
+            config.sensors.len()
+        );
+    }
+
+    let single = devices.len() == 1;
+
+    devices
+        .into_iter()
+        .map(|(name, location, device)| {
+            let bus = device
+                .interface
+                .path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let address = device.interface.address;
+
+            // A single unnamed sensor keeps the original metric prefix and state file
+            let (name, state_file) = match name {
+                Some(name) => {
+                    let state_file = file_for_sensor(&config.state_file, &name);
+                    (name, state_file)
+                }
+                None if single => (String::from(DEFAULT_NAME), config.state_file.clone()),
+                None => {
+                    let name = format!("{}_{:02x}", bus, address);
+                    let state_file = file_for_sensor(&config.state_file, &name);
+                    (name, state_file)
+                }
+            };
+
+            FoundSensor {
+                id: SensorId {
+                    name,
+                    bus,
+                    address,
+                    location,
+                },
+                state_file,
+                device,
+            }
+        })
+        .collect()
+}
+
+/// Name of the simulated sensor on the mock i2c bus.
+pub fn mock_id() -> SensorId {
+    SensorId {
+        name: String::from(DEFAULT_NAME),
+        bus: String::from("mock"),
+        address: bme68x_rust::I2C_ADDR_HIGH,
+        location: None,
+    }
+}
+
+/// The record or state file of one of several sensors, with the sensor name
+/// added to the file name, e.g. `readings_bedroom.csv`.
+pub fn file_for_sensor(file: &Path, name: &str) -> PathBuf {
+    let stem = file
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let file_name = match file.extension() {
+        Some(extension) => format!("{}_{}.{}", stem, name, extension.to_string_lossy()),
+        None => format!("{}_{}", stem, name),
+    };
+    file.with_file_name(file_name)
+}
@@ -0,0 +1,404 @@
+use crate::bsec::{self, bsec_output_t, bsec_virtual_sensor_t};
+use log::{error, info, warn};
+use std::io::Error;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often `Sink::tick` is called.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Identifies a sensor in the outputs.
+#[derive(Clone, Debug)]
+pub struct SensorId {
+    /// Used as the metric prefix and to tell sensors apart in logs.
+    pub name: String,
+    /// The i2c bus, e.g. `i2c-1`.
+    pub bus: String,
+    pub address: u8,
+    pub location: Option<String>,
+}
+
+/// A BSEC virtual sensor output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OutputKind {
+    Iaq,
+    StaticIaq,
+    Co2Equivalent,
+    BreathVocEquivalent,
+    RawTemperature,
+    RawPressure,
+    RawHumidity,
+    RawGas,
+    StabilizationStatus,
+    RunInStatus,
+    HeatCompensatedTemperature,
+    HeatCompensatedHumidity,
+    CompensatedGas,
+    GasPercentage,
+    /// Probability of gas class 0 to 3 in scan mode.
+    GasEstimate(usize),
+    RawGasIndex,
+}
+
+impl OutputKind {
+    pub fn from_sensor_id(sensor_id: u8) -> Option<OutputKind> {
+        let kind = match sensor_id as u32 {
+            bsec_virtual_sensor_t::BSEC_OUTPUT_IAQ => OutputKind::Iaq,
+            bsec_virtual_sensor_t::BSEC_OUTPUT_STATIC_IAQ => OutputKind::StaticIaq,
+            bsec_virtual_sensor_t::BSEC_OUTPUT_CO2_EQUIVALENT => OutputKind::Co2Equivalent,
+            bsec_virtual_sensor_t::BSEC_OUTPUT_BREATH_VOC_EQUIVALENT => {
+                OutputKind::BreathVocEquivalent
+            }
+            bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_TEMPERATURE => OutputKind::RawTemperature,
+            bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_PRESSURE => OutputKind::RawPressure,
+            bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_HUMIDITY => OutputKind::RawHumidity,
+            bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_GAS => OutputKind::RawGas,
+            bsec_virtual_sensor_t::BSEC_OUTPUT_STABILIZATION_STATUS => {
+                OutputKind::StabilizationStatus
+            }
+            bsec_virtual_sensor_t::BSEC_OUTPUT_RUN_IN_STATUS => OutputKind::RunInStatus,
+            bsec_virtual_sensor_t::BSEC_OUTPUT_SENSOR_HEAT_COMPENSATED_TEMPERATURE => {
+                OutputKind::HeatCompensatedTemperature
+            }
+            bsec_virtual_sensor_t::BSEC_OUTPUT_SENSOR_HEAT_COMPENSATED_HUMIDITY => {
+                OutputKind::HeatCompensatedHumidity
+            }
+            bsec_virtual_sensor_t::BSEC_OUTPUT_COMPENSATED_GAS => OutputKind::CompensatedGas,
+            bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_PERCENTAGE => OutputKind::GasPercentage,
+            bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_1 => OutputKind::GasEstimate(0),
+            bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_2 => OutputKind::GasEstimate(1),
+            bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_3 => OutputKind::GasEstimate(2),
+            bsec_virtual_sensor_t::BSEC_OUTPUT_GAS_ESTIMATE_4 => OutputKind::GasEstimate(3),
+            bsec_virtual_sensor_t::BSEC_OUTPUT_RAW_GAS_INDEX => OutputKind::RawGasIndex,
+            _ => return None,
+        };
+        Some(kind)
+    }
+
+    /// The name used in `BSEC_OUTPUTS`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputKind::Iaq => "iaq",
+            OutputKind::StaticIaq => "static_iaq",
+            OutputKind::Co2Equivalent => "co2_equivalent",
+            OutputKind::BreathVocEquivalent => "breath_voc_equivalent",
+            OutputKind::RawTemperature => "raw_temperature",
+            OutputKind::RawPressure => "raw_pressure",
+            OutputKind::RawHumidity => "raw_humidity",
+            OutputKind::RawGas => "raw_gas",
+            OutputKind::StabilizationStatus => "stabilization_status",
+            OutputKind::RunInStatus => "run_in_status",
+            OutputKind::HeatCompensatedTemperature => "heat_compensated_temperature",
+            OutputKind::HeatCompensatedHumidity => "heat_compensated_humidity",
+            OutputKind::CompensatedGas => "compensated_gas",
+            OutputKind::GasPercentage => "gas_percentage",
+            OutputKind::GasEstimate(0) => "gas_estimate_1",
+            OutputKind::GasEstimate(1) => "gas_estimate_2",
+            OutputKind::GasEstimate(2) => "gas_estimate_3",
+            OutputKind::GasEstimate(_) => "gas_estimate_4",
+            OutputKind::RawGasIndex => "raw_gas_index",
+        }
+    }
+
+    /// IAQ and VOC outputs, which are meaningless until BSEC has calibrated.
+    pub fn is_air_quality(&self) -> bool {
+        matches!(
+            self,
+            OutputKind::Iaq
+                | OutputKind::StaticIaq
+                | OutputKind::Co2Equivalent
+                | OutputKind::BreathVocEquivalent
+        )
+    }
+}
+
+/// One output of a `do_steps` call.
+#[derive(Clone, Copy, Debug)]
+pub struct Output {
+    pub kind: OutputKind,
+    pub value: f32,
+    /// Calibration status from 0 to 3, for outputs BSEC reports it for.
+    pub accuracy: Option<u8>,
+}
+
+impl Output {
+    /// Whether an IAQ or VOC value is still below `min_accuracy`, and should
+    /// not be sent. Its accuracy is sent regardless.
+    pub fn below_accuracy(&self, min_accuracy: u8) -> bool {
+        self.kind.is_air_quality() && self.accuracy.unwrap_or(0) < min_accuracy
+    }
+}
+
+/// The outputs of one `do_steps` call of a sensor.
+#[derive(Clone, Debug)]
+pub struct Reading {
+    pub sensor: SensorId,
+    /// BSEC timestamp in nanoseconds.
+    pub timestamp: i64,
+    pub outputs: Vec<Output>,
+}
+
+impl Reading {
+    pub fn new(sensor: &SensorId, timestamp: i64, sensor_outputs: &[bsec_output_t]) -> Reading {
+        let outputs = sensor_outputs
+            .iter()
+            .filter_map(|output| {
+                let kind = OutputKind::from_sensor_id(output.sensor_id);
+                if kind.is_none() {
+                    error!(
+                        "BSEC output {} is not known to this program and is not sent",
+                        output.sensor_id
+                    );
+                }
+                kind.map(|kind| Output {
+                    kind,
+                    value: output.signal,
+                    accuracy: if bsec::reports_accuracy(output.sensor_id) {
+                        Some(output.accuracy)
+                    } else {
+                        None
+                    },
+                })
+            })
+            .collect();
+
+        Reading {
+            sensor: sensor.clone(),
+            timestamp,
+            outputs,
+        }
+    }
+}
+
+/// Health of a sensor after a BSEC cycle.
+#[derive(Clone, Copy, Debug)]
+pub struct SensorStatus {
+    /// Nanosecond timestamp of the last valid reading from the sensor.
+    pub last_read: Option<i64>,
+    /// Return code of the last BSEC call.
+    pub bsec_return_code: i32,
+}
+
+/// An output for readings, run on its own thread by `spawn`.
+pub trait Sink: Send {
+    /// Deliver one reading. Errors are retried according to the
+    /// `SinkOptions` of the sink.
+    fn send(&mut self, reading: &Reading) -> Result<(), Error>;
+
+    /// Take note of the health of a sensor.
+    fn status(&mut self, _sensor: &SensorId, _status: &SensorStatus) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Forget a sensor that was given up on, e.g. its last values.
+    fn remove(&mut self, _sensor: &SensorId) {}
+
+    /// Called about every second, e.g. to flush batches or keep connections
+    /// alive.
+    fn tick(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called once the program stops sending readings.
+    fn close(&mut self) {}
+}
+
+/// Queue size and retries of a sink.
+#[derive(Clone, Copy, Debug)]
+pub struct SinkOptions {
+    /// Messages waiting for the sink, newer ones are dropped when full.
+    pub queue_size: usize,
+    /// Attempts after the first failed send of a reading before it is dropped.
+    pub retries: u32,
+    pub retry_delay: Duration,
+}
+
+impl Default for SinkOptions {
+    fn default() -> Self {
+        SinkOptions {
+            queue_size: 1000,
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+enum Message {
+    Reading(Reading),
+    Status(SensorId, SensorStatus),
+    Remove(SensorId),
+}
+
+/// A sink running on its own thread.
+pub struct SinkHandle {
+    name: &'static str,
+    tx: SyncSender<Message>,
+    thread: JoinHandle<()>,
+}
+
+/// Run `sink` on its own thread, so a slow or dead backend only fills its
+/// own queue.
+pub fn spawn(name: &'static str, mut sink: Box<dyn Sink>, options: SinkOptions) -> SinkHandle {
+    let (tx, rx) = sync_channel::<Message>(options.queue_size);
+
+    let thread = thread::spawn(move || {
+        info!("Started {} output.", name);
+        run(name, sink.as_mut(), rx, options);
+        sink.close();
+        info!("Stopped {} output.", name);
+    });
+
+    SinkHandle { name, tx, thread }
+}
+
+fn run(name: &str, sink: &mut dyn Sink, rx: Receiver<Message>, options: SinkOptions) {
+    let mut last_tick = Instant::now();
+
+    loop {
+        match rx.recv_timeout(TICK_INTERVAL) {
+            Ok(Message::Reading(reading)) => {
+                let mut attempt = 0;
+                loop {
+                    match sink.send(&reading) {
+                        Ok(()) => break,
+                        Err(e) if attempt < options.retries => {
+                            attempt += 1;
+                            warn!(
+                                "{}: failed to send reading, retry {} of {}: {}",
+                                name, attempt, options.retries, e
+                            );
+                            thread::sleep(options.retry_delay);
+                        }
+                        Err(e) => {
+                            error!("{}: dropping reading: {}", name, e);
+                            break;
+                        }
+                    }
+                }
+            }
+            Ok(Message::Status(sensor, status)) => {
+                if let Err(e) = sink.status(&sensor, &status) {
+                    warn!("{}: failed to update sensor status: {}", name, e);
+                }
+            }
+            Ok(Message::Remove(sensor)) => sink.remove(&sensor),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if last_tick.elapsed() >= TICK_INTERVAL {
+            last_tick = Instant::now();
+            if let Err(e) = sink.tick() {
+                warn!("{}: {}", name, e);
+            }
+        }
+    }
+}
+
+/// All enabled sinks.
+#[derive(Default)]
+pub struct Sinks {
+    handles: Vec<SinkHandle>,
+}
+
+impl Sinks {
+    pub fn add(&mut self, handle: SinkHandle) {
+        self.handles.push(handle);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    pub fn send(&self, reading: Reading) {
+        for handle in self.handles.iter() {
+            handle.push(Message::Reading(reading.clone()));
+        }
+    }
+
+    /// Like `send`, but waits for room in full queues instead of dropping
+    /// the reading. Used to replay recordings.
+    pub fn send_wait(&self, reading: Reading) {
+        for handle in self.handles.iter() {
+            if handle.tx.send(Message::Reading(reading.clone())).is_err() {
+                error!("{} output has stopped", handle.name);
+            }
+        }
+    }
+
+    pub fn status(&self, sensor: &SensorId, status: SensorStatus) {
+        for handle in self.handles.iter() {
+            handle.push(Message::Status(sensor.clone(), status));
+        }
+    }
+
+    pub fn remove(&self, sensor: &SensorId) {
+        for handle in self.handles.iter() {
+            handle.push(Message::Remove(sensor.clone()));
+        }
+    }
+
+    /// Let every sink finish its queue and wait for it to stop.
+    pub fn close(self) {
+        // Close every queue first so the sinks flush in parallel
+        let threads: Vec<(&'static str, JoinHandle<()>)> = self
+            .handles
+            .into_iter()
+            .map(|handle| (handle.name, handle.thread))
+            .collect();
+
+        for (name, thread) in threads {
+            thread
+                .join()
+                .unwrap_or_else(|_| error!("{} output thread panicked.", name));
+        }
+    }
+
+    /// Like `close`, but give up on sinks that have not stopped within
+    /// `timeout`, e.g. while retrying an unreachable server.
+    pub fn close_within(self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+
+        let mut threads: Vec<(&'static str, JoinHandle<()>)> = self
+            .handles
+            .into_iter()
+            .map(|handle| (handle.name, handle.thread))
+            .collect();
+
+        while !threads.is_empty() && Instant::now() < deadline {
+            let (finished, running) = threads
+                .into_iter()
+                .partition(|(_, thread)| thread.is_finished());
+            threads = running;
+
+            for (name, thread) in finished {
+                thread
+                    .join()
+                    .unwrap_or_else(|_| error!("{} output thread panicked.", name));
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        for (name, _) in threads {
+            warn!(
+                "{} output did not stop within {} s, its queue is lost.",
+                name,
+                timeout.as_secs()
+            );
+        }
+    }
+}
+
+impl SinkHandle {
+    fn push(&self, message: Message) {
+        match self.tx.try_send(message) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!("{} output queue full, dropping reading", self.name)
+            }
+            Err(TrySendError::Disconnected(_)) => error!("{} output has stopped", self.name),
+        }
+    }
+}
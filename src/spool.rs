@@ -0,0 +1,511 @@
+use crate::config::SpoolConfig;
+use chrono::Utc;
+use log::{debug, info, warn};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, Write};
+use std::path::PathBuf;
+
+/// Segments are closed once they grow past this size, and are the unit in
+/// which old points are dropped.
+const SEGMENT_BYTES: u64 = 256 * 1024;
+
+/// One file of the spool, named after its sequence number.
+struct Segment {
+    sequence: u64,
+    path: PathBuf,
+    bytes: u64,
+    points: u64,
+    /// Unix time of the oldest and newest point in the segment.
+    oldest: i64,
+    newest: i64,
+}
+
+/// Graphite plaintext lines that could not be sent yet, kept on disk so they
+/// survive a restart. Lines are appended to numbered segment files, and the
+/// oldest segments are dropped when the spool grows past `max_bytes` or its
+/// points get older than `max_age`.
+pub struct Spool {
+    config: SpoolConfig,
+    /// Oldest first, new lines go to the last one.
+    segments: VecDeque<Segment>,
+    /// Points dropped since the program started.
+    dropped: u64,
+}
+
+impl Spool {
+    /// Open the spool in `config.dir`, picking up the segments left by an
+    /// earlier run.
+    pub fn open(config: SpoolConfig) -> Result<Spool, Error> {
+        fs::create_dir_all(&config.dir)?;
+
+        let mut segments = Vec::new();
+
+        for entry in fs::read_dir(&config.dir)? {
+            let path = entry?.path();
+
+            // Left by a crash in `rewrite_oldest`, the segment itself is intact
+            if path.extension().and_then(|extension| extension.to_str()) == Some("tmp") {
+                debug!("Removing {}", path.display());
+                fs::remove_file(&path)?;
+                continue;
+            }
+
+            let sequence = match path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".spool"))
+                .and_then(|sequence| sequence.parse::<u64>().ok())
+            {
+                Some(sequence) => sequence,
+                None => continue,
+            };
+
+            let contents = fs::read_to_string(&path)?;
+            let timestamps: Vec<i64> = contents.lines().filter_map(timestamp).collect();
+
+            if timestamps.is_empty() {
+                fs::remove_file(&path)?;
+                continue;
+            }
+
+            segments.push(Segment {
+                sequence,
+                path,
+                bytes: contents.len() as u64,
+                points: timestamps.len() as u64,
+                oldest: timestamps.iter().copied().min().unwrap_or(0),
+                newest: timestamps.iter().copied().max().unwrap_or(0),
+            });
+        }
+
+        segments.sort_by_key(|segment| segment.sequence);
+
+        let mut spool = Spool {
+            config,
+            segments: segments.into(),
+            dropped: 0,
+        };
+
+        if !spool.is_empty() {
+            info!(
+                "Found {} unsent Graphite points in {}",
+                spool.points(),
+                spool.config.dir.display()
+            );
+        }
+
+        spool.expire();
+        spool.shrink();
+
+        Ok(spool)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Points waiting to be sent.
+    pub fn points(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.points).sum()
+    }
+
+    /// Points dropped for age or size since the program started.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn bytes(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.bytes).sum()
+    }
+
+    /// Append plaintext lines, dropping the oldest segments if the spool
+    /// gets too big.
+    pub fn push(&mut self, lines: &str) -> Result<(), Error> {
+        let timestamps: Vec<i64> = lines.lines().filter_map(timestamp).collect();
+        if timestamps.is_empty() {
+            return Ok(());
+        }
+
+        let full = !matches!(self.segments.back(), Some(segment) if segment.bytes < SEGMENT_BYTES);
+        if full {
+            let sequence = self
+                .segments
+                .back()
+                .map_or(0, |segment| segment.sequence + 1);
+            self.segments.push_back(Segment {
+                sequence,
+                path: self.config.dir.join(format!("{:016}.spool", sequence)),
+                bytes: 0,
+                points: 0,
+                oldest: i64::MAX,
+                newest: 0,
+            });
+        }
+
+        let segment = self.segments.back_mut().unwrap();
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&segment.path)?;
+        file.write_all(lines.as_bytes())?;
+        if !lines.ends_with('\n') {
+            file.write_all(b"\n")?;
+        }
+
+        segment.bytes += lines.len() as u64;
+        segment.points += timestamps.len() as u64;
+        segment.oldest = segment
+            .oldest
+            .min(timestamps.iter().copied().min().unwrap_or(i64::MAX));
+        segment.newest = segment
+            .newest
+            .max(timestamps.iter().copied().max().unwrap_or(0));
+
+        self.shrink();
+
+        Ok(())
+    }
+
+    /// Drop segments whose points are all older than `max_age`.
+    pub fn expire(&mut self) {
+        let oldest_allowed = Utc::now().timestamp() - self.config.max_age.as_secs() as i64;
+
+        while let Some(segment) = self.segments.front() {
+            if segment.newest >= oldest_allowed {
+                break;
+            }
+            warn!(
+                "Dropping {} Graphite points older than {}s from the spool",
+                segment.points,
+                self.config.max_age.as_secs()
+            );
+            self.drop_oldest();
+        }
+    }
+
+    /// Drop the oldest segments until the spool fits in `max_bytes`.
+    fn shrink(&mut self) {
+        while self.bytes() > self.config.max_bytes && self.segments.len() > 1 {
+            if let Some(segment) = self.segments.front() {
+                warn!(
+                    "Graphite spool is full, dropping {} oldest points",
+                    segment.points
+                );
+            }
+            self.drop_oldest();
+        }
+    }
+
+    fn drop_oldest(&mut self) {
+        if let Some(segment) = self.segments.pop_front() {
+            self.dropped += segment.points;
+            if let Err(e) = fs::remove_file(&segment.path) {
+                warn!("Failed to remove {}: {}", segment.path.display(), e);
+            }
+        }
+    }
+
+    /// Pass the spooled lines to `send` in timestamp order, up to
+    /// `batch_lines` at a time, and remove them once sent. Stops at the first
+    /// error, leaving the unsent lines in the spool.
+    pub fn drain<F>(&mut self, batch_lines: usize, mut send: F) -> Result<(), Error>
+    where
+        F: FnMut(&str) -> Result<(), Error>,
+    {
+        self.expire();
+
+        let oldest_allowed = Utc::now().timestamp() - self.config.max_age.as_secs() as i64;
+
+        while !self.segments.is_empty() {
+            let merged = self.overlapping();
+
+            let mut contents = Vec::with_capacity(merged);
+            for segment in self.segments.iter().take(merged) {
+                contents.push(fs::read_to_string(&segment.path)?);
+            }
+
+            // Readings of several sensors can arrive slightly out of order,
+            // and a late one can end up in a later segment
+            let mut lines: Vec<(i64, &str)> = contents
+                .iter()
+                .flat_map(|contents| contents.lines())
+                .filter_map(|line| timestamp(line).map(|timestamp| (timestamp, line)))
+                .collect();
+            lines.sort_by_key(|(timestamp, _)| *timestamp);
+
+            let total = lines.len();
+            lines.retain(|(timestamp, _)| *timestamp >= oldest_allowed);
+            self.dropped += (total - lines.len()) as u64;
+
+            for (index, batch) in lines.chunks(batch_lines).enumerate() {
+                let mut text = String::new();
+                for (_, line) in batch {
+                    text.push_str(line);
+                    text.push('\n');
+                }
+
+                if let Err(e) = send(&text) {
+                    // Keep what is left, so the next drain resumes here
+                    let remaining = &lines[index * batch_lines..];
+                    self.rewrite_oldest(remaining, merged)?;
+                    return Err(e);
+                }
+            }
+
+            debug!("Sent {} spooled Graphite points", lines.len());
+
+            for segment in self.segments.drain(..merged) {
+                fs::remove_file(&segment.path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Number of segments, oldest first, whose lines have to be sorted
+    /// together, as no later segment has a line older than their newest.
+    fn overlapping(&self) -> usize {
+        let mut merged = 1;
+        let mut newest = self.segments.front().map_or(0, |segment| segment.newest);
+        let mut seen = newest;
+
+        for (index, segment) in self.segments.iter().enumerate().skip(1) {
+            if segment.oldest < newest {
+                merged = index + 1;
+                newest = seen.max(segment.newest);
+            }
+            seen = seen.max(segment.newest);
+        }
+
+        merged
+    }
+
+    /// Replace the oldest `merged` segments with `lines` in the oldest one,
+    /// through a synced temporary file so a crash leaves either the old or
+    /// the new contents.
+    fn rewrite_oldest(&mut self, lines: &[(i64, &str)], merged: usize) -> Result<(), Error> {
+        let segment = match self.segments.front_mut() {
+            Some(segment) => segment,
+            None => return Ok(()),
+        };
+
+        let mut contents = String::new();
+        for (_, line) in lines {
+            contents.push_str(line);
+            contents.push('\n');
+        }
+
+        let temp_path = segment.path.with_extension("tmp");
+        let mut file = File::create(&temp_path)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&temp_path, &segment.path)?;
+        File::open(&self.config.dir)?.sync_all()?;
+
+        segment.bytes = contents.len() as u64;
+        segment.points = lines.len() as u64;
+        segment.oldest = lines.first().map_or(0, |(timestamp, _)| *timestamp);
+        segment.newest = lines.last().map_or(0, |(timestamp, _)| *timestamp);
+
+        // Their lines are in the oldest segment now, a crash before this
+        // only sends them twice
+        for segment in self.segments.drain(1..merged) {
+            fs::remove_file(&segment.path)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Timestamp of a plaintext line `<path> <value> <timestamp>`, `None` for
+/// lines that are cut off or otherwise malformed.
+fn timestamp(line: &str) -> Option<i64> {
+    let mut parts = line.split(' ');
+    let (_path, _value, timestamp) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+    timestamp.parse::<i64>().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+    use std::path::Path;
+    use std::time::Duration;
+
+    /// An empty spool directory for one test.
+    fn spool_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bme-spool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(dir: &Path, max_bytes: u64) -> Spool {
+        Spool::open(SpoolConfig {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            max_age: Duration::from_secs(3600),
+        })
+        .unwrap()
+    }
+
+    fn line(name: &str, timestamp: i64) -> String {
+        format!("bme.{} 1 {}\n", name, timestamp)
+    }
+
+    /// Lines of 19 bytes, enough to fill a segment.
+    const FILL_LINES: u64 = SEGMENT_BYTES / 19 + 1;
+
+    fn fill(timestamp: i64) -> String {
+        (0..FILL_LINES).map(|_| line("a", timestamp)).collect()
+    }
+
+    /// Drain the spool, returning the lines sent.
+    fn drain_all(spool: &mut Spool) -> Vec<String> {
+        let mut sent = Vec::new();
+        spool
+            .drain(2, |batch| {
+                sent.extend(batch.lines().map(String::from));
+                Ok(())
+            })
+            .unwrap();
+        sent
+    }
+
+    #[test]
+    fn push_survives_reopen() {
+        let dir = spool_dir("push");
+        let now = Utc::now().timestamp();
+
+        let mut spool = open(&dir, 1 << 20);
+        spool.push(&line("a", now)).unwrap();
+        spool.push(&(line("b", now) + "cut off\n")).unwrap();
+        assert_eq!(spool.points(), 2);
+
+        let mut spool = open(&dir, 1 << 20);
+        assert_eq!(spool.points(), 2);
+        assert_eq!(
+            drain_all(&mut spool),
+            [format!("bme.a 1 {}", now), format!("bme.b 1 {}", now)]
+        );
+        assert!(spool.is_empty());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shrink_drops_oldest_segments() {
+        let dir = spool_dir("shrink");
+        let now = Utc::now().timestamp();
+
+        let mut spool = open(&dir, SEGMENT_BYTES + SEGMENT_BYTES / 2);
+        spool.push(&fill(now)).unwrap();
+        assert_eq!(spool.dropped(), 0);
+
+        // A second full segment does not fit, the first is dropped
+        spool.push(&fill(now)).unwrap();
+        assert_eq!(spool.segments.len(), 1);
+        assert_eq!(spool.points(), FILL_LINES);
+        assert_eq!(spool.dropped(), FILL_LINES);
+
+        spool.push(&line("b", now)).unwrap();
+        assert_eq!(spool.segments.len(), 2);
+        assert_eq!(spool.dropped(), FILL_LINES);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn expire_drops_old_segments() {
+        let dir = spool_dir("expire");
+        let now = Utc::now().timestamp();
+
+        let mut spool = open(&dir, 1 << 20);
+        spool.push(&fill(now - 7200)).unwrap();
+        spool.push(&line("b", now)).unwrap();
+        assert_eq!(spool.segments.len(), 2);
+
+        spool.expire();
+        assert_eq!(spool.points(), 1);
+        assert_eq!(spool.dropped(), FILL_LINES);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drain_sorts_across_segments() {
+        let dir = spool_dir("sort");
+        let now = Utc::now().timestamp();
+
+        let mut spool = open(&dir, 1 << 20);
+        spool.push(&line("a", now - 10)).unwrap();
+        spool.push(&fill(now)).unwrap();
+        // Late, in the second segment but older than most of the first
+        spool.push(&line("late", now - 5)).unwrap();
+        spool.push(&line("b", now + 10)).unwrap();
+        assert_eq!(spool.segments.len(), 2);
+
+        let sent = drain_all(&mut spool);
+        assert_eq!(sent.len() as u64, FILL_LINES + 3);
+        assert_eq!(sent[0], format!("bme.a 1 {}", now - 10));
+        assert_eq!(sent[1], format!("bme.late 1 {}", now - 5));
+        assert_eq!(sent.last().unwrap(), &format!("bme.b 1 {}", now + 10));
+        assert!(spool.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drain_resumes_after_error() {
+        let dir = spool_dir("resume");
+        let now = Utc::now().timestamp();
+
+        let mut spool = open(&dir, 1 << 20);
+        for i in 0..5 {
+            spool.push(&line("a", now + i)).unwrap();
+        }
+
+        let mut batches = 0;
+        let result = spool.drain(2, |_| {
+            batches += 1;
+            if batches == 2 {
+                return Err(Error::new(ErrorKind::ConnectionReset, "gone"));
+            }
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert_eq!(spool.points(), 3);
+
+        // The unsent lines are kept on disk
+        let mut spool = open(&dir, 1 << 20);
+        assert_eq!(
+            drain_all(&mut spool),
+            (2..5)
+                .map(|i| format!("bme.a 1 {}", now + i))
+                .collect::<Vec<_>>()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn open_removes_temp_files() {
+        let dir = spool_dir("tmp");
+        let now = Utc::now().timestamp();
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("0000000000000000.spool"), line("a", now)).unwrap();
+        fs::write(dir.join("0000000000000000.tmp"), line("a", now)).unwrap();
+
+        let spool = open(&dir, 1 << 20);
+        assert_eq!(spool.points(), 1);
+        assert!(!dir.join("0000000000000000.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
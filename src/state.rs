@@ -0,0 +1,165 @@
+use crate::bsec::bsec_version_t;
+use chrono::Utc;
+use flate2::Crc;
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Start of a state file with a header. Files without it are the bare BSEC
+/// blobs of earlier versions.
+const MAGIC: &[u8; 4] = b"BSST";
+const FORMAT_VERSION: u8 = 1;
+/// Magic, format version, BSEC version, timestamp, blob length and CRC.
+const HEADER_LEN: usize = 4 + 1 + 4 + 8 + 4 + 4;
+
+/// A BSEC state blob as read from a state file.
+pub struct SavedState {
+    pub blob: Vec<u8>,
+    /// BSEC version that produced the blob, unknown for bare blobs.
+    pub bsec_version: Option<[u8; 4]>,
+    /// Unix time the blob was saved at, unknown for bare blobs.
+    pub saved: Option<i64>,
+}
+
+/// Read a state file, checking its length and CRC.
+pub fn read(path: &Path) -> Result<SavedState, Error> {
+    let data = fs::read(path)?;
+
+    if !data.starts_with(MAGIC) {
+        if data.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "file is empty"));
+        }
+        return Ok(SavedState {
+            blob: data,
+            bsec_version: None,
+            saved: None,
+        });
+    }
+
+    if data.len() < HEADER_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, "header is truncated"));
+    }
+    if data[4] != FORMAT_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unknown format version {}", data[4]),
+        ));
+    }
+
+    let bsec_version = [data[5], data[6], data[7], data[8]];
+    let saved = i64::from_le_bytes(data[9..17].try_into().unwrap());
+    let length = u32::from_le_bytes(data[17..21].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(data[21..25].try_into().unwrap());
+    let blob = &data[HEADER_LEN..];
+
+    if blob.len() != length {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("expected {} bytes of state, found {}", length, blob.len()),
+        ));
+    }
+    if checksum(&data[4..21], blob) != crc {
+        return Err(Error::new(ErrorKind::InvalidData, "CRC mismatch"));
+    }
+
+    Ok(SavedState {
+        blob: blob.to_vec(),
+        bsec_version: Some(bsec_version),
+        saved: Some(saved),
+    })
+}
+
+/// Replace the state file with `blob` so that a crash at any point leaves
+/// either the old or the new file, and keep the last good file as the first
+/// of `backups` numbered backups.
+pub fn write(
+    path: &Path,
+    blob: &[u8],
+    bsec_version: &bsec_version_t,
+    backups: usize,
+) -> Result<(), Error> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.push(FORMAT_VERSION);
+    header.extend_from_slice(&version_bytes(bsec_version));
+    header.extend_from_slice(&Utc::now().timestamp().to_le_bytes());
+    header.extend_from_slice(&(blob.len() as u32).to_le_bytes());
+    let crc = checksum(&header[4..], blob);
+    header.extend_from_slice(&crc.to_le_bytes());
+
+    let temp_file = with_suffix(path, ".tmp");
+
+    let mut file = File::create(&temp_file)?;
+    file.write_all(&header)?;
+    file.write_all(blob)?;
+    file.sync_all()?;
+    drop(file);
+
+    // A damaged file is replaced rather than pushing out a good backup
+    if backups > 0 && read(path).is_ok() {
+        for i in (1..backups).rev() {
+            let older = backup_file(path, i);
+            if older.exists() {
+                fs::rename(&older, backup_file(path, i + 1))?;
+            }
+        }
+        fs::rename(path, backup_file(path, 1))?;
+    }
+
+    fs::rename(&temp_file, path)?;
+
+    // Make the renames durable too
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// The state file followed by its numbered backups, in the order to try
+/// loading them.
+pub fn candidates(path: &Path, backups: usize) -> Vec<PathBuf> {
+    let mut files = vec![path.to_path_buf()];
+    files.extend(
+        (1..=backups)
+            .map(|i| backup_file(path, i))
+            .filter(|file| file.exists()),
+    );
+    files
+}
+
+/// e.g. `last_state.bin.1`
+pub fn backup_file(path: &Path, number: usize) -> PathBuf {
+    with_suffix(path, &format!(".{}", number))
+}
+
+pub fn version_bytes(version: &bsec_version_t) -> [u8; 4] {
+    [
+        version.major,
+        version.minor,
+        version.major_bugfix,
+        version.minor_bugfix,
+    ]
+}
+
+pub fn version_name(version: [u8; 4]) -> String {
+    format!(
+        "{}.{}.{}.{}",
+        version[0], version[1], version[2], version[3]
+    )
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn checksum(header: &[u8], blob: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(header);
+    crc.update(blob);
+    crc.sum()
+}
@@ -0,0 +1,189 @@
+use log::{debug, warn};
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::raw::{c_int, c_long};
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::time::{Duration, Instant};
+
+/// Tells systemd about the state of a `Type=notify` service through
+/// `$NOTIFY_SOCKET`, see `sd_notify(3)`.
+pub struct Notifier {
+    socket: UnixDatagram,
+    address: SocketAddr,
+    /// How often to ping the watchdog, half of `WatchdogSec`.
+    watchdog_interval: Option<Duration>,
+    last_ping: Instant,
+    last_status: String,
+}
+
+impl Notifier {
+    /// `None` unless started by systemd as a notify service.
+    pub fn from_env() -> Option<Notifier> {
+        let path = env::var_os("NOTIFY_SOCKET")?;
+
+        let address = match path.to_str().and_then(|path| path.strip_prefix('@')) {
+            Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+            None => SocketAddr::from_pathname(&path),
+        };
+        let socket = UnixDatagram::unbound();
+
+        let (socket, address) = match (socket, address) {
+            (Ok(socket), Ok(address)) => (socket, address),
+            (Err(e), _) | (_, Err(e)) => {
+                warn!("Cannot notify systemd on {:?}: {}", path, e);
+                return None;
+            }
+        };
+
+        // The watchdog may be meant for another process, e.g. a wrapper script
+        let for_us = match env::var("WATCHDOG_PID") {
+            Ok(pid) => pid.trim().parse() == Ok(process::id()),
+            Err(_) => true,
+        };
+        let watchdog_interval = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.trim().parse::<u64>().ok())
+            .filter(|usec| *usec > 0 && for_us)
+            .map(|usec| Duration::from_micros(usec / 2));
+
+        Some(Notifier {
+            socket,
+            address,
+            watchdog_interval,
+            last_ping: Instant::now(),
+            last_status: String::new(),
+        })
+    }
+
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog_interval
+    }
+
+    /// The sensors are set up and measuring.
+    pub fn ready(&mut self) {
+        self.notify("READY=1");
+    }
+
+    /// The config is being read again, `ready` ends the reload.
+    pub fn reloading(&mut self) {
+        self.notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", monotonic_usec()));
+    }
+
+    pub fn stopping(&mut self) {
+        self.notify("STOPPING=1");
+    }
+
+    /// Shown by `systemctl status`, only sent when it changes.
+    pub fn status(&mut self, status: &str) {
+        if status != self.last_status {
+            self.notify(&format!("STATUS={}", status));
+            self.last_status = status.to_string();
+        }
+    }
+
+    /// Tell the watchdog the main loop is alive, if it is time to.
+    pub fn ping(&mut self) {
+        if let Some(interval) = self.watchdog_interval {
+            if self.last_ping.elapsed() >= interval {
+                self.notify("WATCHDOG=1");
+                self.last_ping = Instant::now();
+            }
+        }
+    }
+
+    fn notify(&mut self, state: &str) {
+        debug!("Notifying systemd: {}", state);
+
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.address) {
+            warn!("Cannot notify systemd: {}", e);
+        }
+    }
+}
+
+/// `CLOCK_MONOTONIC` in microseconds, which systemd needs with `RELOADING=1`
+/// to tell a reload from an older one.
+fn monotonic_usec() -> u64 {
+    #[repr(C)]
+    struct Timespec {
+        tv_sec: c_long,
+        tv_nsec: c_long,
+    }
+
+    extern "C" {
+        fn clock_gettime(clock: c_int, time: *mut Timespec) -> c_int;
+    }
+
+    const CLOCK_MONOTONIC: c_int = 1;
+
+    let mut time = Timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Cannot fail for CLOCK_MONOTONIC with a valid pointer
+    unsafe { clock_gettime(CLOCK_MONOTONIC, &mut time) };
+
+    time.tv_sec as u64 * 1_000_000 + time.tv_nsec as u64 / 1_000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn receive(socket: &UnixDatagram) -> String {
+        let mut buffer = [0u8; 256];
+        let length = socket.recv(&mut buffer).unwrap();
+        String::from_utf8(buffer[..length].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn notifies_socket() {
+        let path = env::temp_dir().join(format!("bme-notify-{}.sock", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        env::set_var("NOTIFY_SOCKET", &path);
+        env::set_var("WATCHDOG_USEC", "20000");
+        env::remove_var("WATCHDOG_PID");
+        let mut notifier = Notifier::from_env().unwrap();
+        assert_eq!(
+            notifier.watchdog_interval(),
+            Some(Duration::from_millis(10))
+        );
+
+        notifier.ready();
+        assert_eq!(receive(&socket), "READY=1");
+
+        notifier.status("Measuring");
+        notifier.status("Measuring");
+        notifier.status("Sensor study failing");
+        assert_eq!(receive(&socket), "STATUS=Measuring");
+        assert_eq!(receive(&socket), "STATUS=Sensor study failing");
+
+        thread::sleep(Duration::from_millis(10));
+        notifier.ping();
+        notifier.ping();
+        assert_eq!(receive(&socket), "WATCHDOG=1");
+
+        let before = monotonic_usec();
+        notifier.reloading();
+        let reloading = receive(&socket);
+        let usec = reloading
+            .strip_prefix("RELOADING=1\nMONOTONIC_USEC=")
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+        assert!(usec >= before && usec <= monotonic_usec());
+
+        notifier.stopping();
+        assert_eq!(receive(&socket), "STOPPING=1");
+
+        env::remove_var("NOTIFY_SOCKET");
+        env::remove_var("WATCHDOG_USEC");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,2 +1,3 @@
 #include "lib/bsec_datatypes.h"
 #include "lib/bsec_interface.h"
+#include "lib/bsec_interface_multi.h"